@@ -0,0 +1,194 @@
+use support::{decl_module, decl_storage, decl_event, StorageValue, StorageMap, dispatch::Result};
+use rstd::result;
+use runtime_primitives::traits::{Lookup, StaticLookup, CurrentHeight, BlockNumberToHash};
+use crate::validatorset;
+
+pub trait Trait: validatorset::Trait {
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+}
+
+decl_storage! {
+	trait Store for Module<T: Trait> as Allowlist {
+		/// Whether only allowed accounts and validators can send signed transactions.
+		Enabled get(enabled) config(): bool;
+		/// Accounts allowed to send signed transactions.
+		Allowed get(allowed) config(): map T::AccountId => bool;
+	}
+	extra_genesis_skip_phantom_data_field;
+}
+
+decl_event!(
+  pub enum Event<T> where AccountId = <T as system::Trait>::AccountId {
+	  // Account allowed to send transactions.
+	  AccountAllowed(AccountId),
+
+	  // Account no longer allowed to send transactions.
+	  AccountDisallowed(AccountId),
+
+	  // Allowlist turned on or off.
+	  AllowlistEnabled(bool),
+  }
+);
+
+decl_module! {
+	pub struct Module<T: Trait> for enum Call where origin: T::Origin {
+		fn deposit_event<T>() = default;
+
+		/// Allow an account to send transactions using root privileges.
+		///
+		/// Validators can vote on this through the council or a multisig root proposal.
+		pub fn allow_account(who: T::AccountId) -> Result {
+			<Allowed<T>>::insert(who.clone(), true);
+
+			Self::deposit_event(RawEvent::AccountAllowed(who));
+			Ok(())
+		}
+
+		/// Revoke an account's permission to send transactions using root privileges.
+		pub fn disallow_account(who: T::AccountId) -> Result {
+			<Allowed<T>>::remove(who.clone());
+
+			Self::deposit_event(RawEvent::AccountDisallowed(who));
+			Ok(())
+		}
+
+		/// Turn the allowlist on or off using root privileges.
+		pub fn set_enabled(enabled: bool) -> Result {
+			<Enabled<T>>::put(enabled);
+
+			Self::deposit_event(RawEvent::AllowlistEnabled(enabled));
+			Ok(())
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	/// Whether the account may send signed transactions.
+	///
+	/// Validators are always permitted so that they can keep governing the chain,
+	/// e.g. through `ValidatorSet` proposals.
+	pub fn is_permitted(who: &T::AccountId) -> bool {
+		!Self::enabled()
+			|| Self::allowed(who)
+			|| <validatorset::Module<T>>::is_validator(who)
+	}
+}
+
+/// Checking context of the executive.
+///
+/// Behaves like `system::ChainContext`, except that looking up the signer of a
+/// transaction fails if the signer is not permitted by the allowlist. Signature
+/// checking uses it both when validating transactions for the pool and when
+/// applying them in blocks, so the allowlist is enforced on both paths.
+pub struct ChainContext<T>(system::ChainContext<T>);
+
+impl<T> Default for ChainContext<T> where system::ChainContext<T>: Default {
+	fn default() -> Self {
+		ChainContext(Default::default())
+	}
+}
+
+impl<T: Trait> Lookup for ChainContext<T> {
+	type Source = <T::Lookup as StaticLookup>::Source;
+	type Target = T::AccountId;
+
+	fn lookup(&self, s: Self::Source) -> result::Result<Self::Target, &'static str> {
+		let who = self.0.lookup(s)?;
+		if <Module<T>>::is_permitted(&who) {
+			Ok(who)
+		} else {
+			Err("account is not allowed to send transactions")
+		}
+	}
+}
+
+impl<T: Trait> CurrentHeight for ChainContext<T> {
+	type BlockNumber = T::BlockNumber;
+
+	fn current_height(&self) -> Self::BlockNumber {
+		self.0.current_height()
+	}
+}
+
+impl<T: Trait> BlockNumberToHash for ChainContext<T> {
+	type BlockNumber = T::BlockNumber;
+	type Hash = T::Hash;
+
+	fn block_number_to_hash(&self, n: Self::BlockNumber) -> Option<Self::Hash> {
+		self.0.block_number_to_hash(n)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use support::assert_ok;
+	use runtime_primitives::traits::Dispatchable;
+	use crate::mock::{new_test_ext, account, signed, events, ROOT};
+	use crate::{Runtime, Call, Event, Indices, Allowlist};
+	use super::Call as AllowlistCall;
+
+	fn lookup(n: u8) -> result::Result<crate::AccountId, &'static str> {
+		ChainContext::<Runtime>::default().lookup(Indices::unlookup(account(n)))
+	}
+
+	#[test]
+	fn allowlist_is_only_enforced_when_enabled() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert!(Allowlist::is_permitted(&account(50)));
+
+			assert_ok!(Allowlist::set_enabled(true));
+			assert!(!Allowlist::is_permitted(&account(50)));
+
+			assert_ok!(Allowlist::allow_account(account(50)));
+			assert!(Allowlist::is_permitted(&account(50)));
+			assert!(events().contains(&Event::allowlist(RawEvent::AccountAllowed(account(50)))));
+
+			assert_ok!(Allowlist::disallow_account(account(50)));
+			assert!(!Allowlist::is_permitted(&account(50)));
+		});
+	}
+
+	#[test]
+	fn validators_are_always_permitted() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_ok!(Allowlist::set_enabled(true));
+
+			for n in 1..=3 {
+				assert!(Allowlist::is_permitted(&account(n)));
+			}
+			assert!(!Allowlist::is_permitted(&account(ROOT)));
+		});
+	}
+
+	#[test]
+	fn lookup_rejects_senders_not_on_the_list() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_eq!(lookup(ROOT), Ok(account(ROOT)));
+
+			assert_ok!(Allowlist::set_enabled(true));
+			assert_eq!(lookup(ROOT), Err("account is not allowed to send transactions"));
+			assert_eq!(lookup(1), Ok(account(1)));
+
+			assert_ok!(Allowlist::allow_account(account(ROOT)));
+			assert_eq!(lookup(ROOT), Ok(account(ROOT)));
+		});
+	}
+
+	#[test]
+	fn only_root_changes_the_list() {
+		with_externalities(&mut new_test_ext(3), || {
+			for call in vec![
+				AllowlistCall::allow_account(account(50)),
+				AllowlistCall::disallow_account(account(50)),
+				AllowlistCall::set_enabled(true),
+			] {
+				assert!(Call::Allowlist(call.clone()).dispatch(signed(1)).is_err());
+				assert!(Call::Allowlist(call.clone()).dispatch(signed(ROOT)).is_err());
+				assert_ok!(Call::Allowlist(call).dispatch(system::RawOrigin::Root.into()));
+			}
+			assert!(Allowlist::enabled());
+		});
+	}
+}
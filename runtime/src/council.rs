@@ -0,0 +1,416 @@
+use support::{decl_module, decl_storage, decl_event, StorageValue, StorageMap, ensure, Parameter, dispatch::Result};
+use rstd::prelude::*;
+use system::{ensure_signed};
+use runtime_primitives::traits::{As, Hash, Dispatchable};
+use parity_codec::{Codec, Encode, Decode};
+use crate::validatorset;
+
+/// Approvals of an open proposal.
+#[derive(Encode, Decode, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct Votes<AccountId, BlockNumber> {
+	/// Validator that created the proposal.
+	pub proposer: AccountId,
+	/// Validators that approved the proposal, starting with the proposer.
+	pub approvals: Vec<AccountId>,
+	/// Block at which the proposal expires.
+	pub expiry: BlockNumber,
+}
+
+impl<AccountId: PartialEq + Clone, BlockNumber> Votes<AccountId, BlockNumber> {
+	/// Votes of a new proposal, approved by its proposer.
+	pub fn new(proposer: AccountId, expiry: BlockNumber) -> Self {
+		Votes { approvals: vec![proposer.clone()], proposer, expiry }
+	}
+
+	/// Add the approval of `who`.
+	pub fn approve(&mut self, who: AccountId) -> Result {
+		add_vote(&mut self.approvals, who)
+	}
+}
+
+/// Details of an open proposal that hold its votes.
+pub trait ProposalVotes {
+	type AccountId: PartialEq + Clone;
+	type BlockNumber: PartialOrd;
+
+	/// Votes of the proposal.
+	fn votes(&self) -> &Votes<Self::AccountId, Self::BlockNumber>;
+
+	/// Mutable votes of the proposal.
+	fn votes_mut(&mut self) -> &mut Votes<Self::AccountId, Self::BlockNumber>;
+}
+
+impl<AccountId: PartialEq + Clone, BlockNumber: PartialOrd> ProposalVotes for Votes<AccountId, BlockNumber> {
+	type AccountId = AccountId;
+	type BlockNumber = BlockNumber;
+
+	fn votes(&self) -> &Self {
+		self
+	}
+
+	fn votes_mut(&mut self) -> &mut Self {
+		self
+	}
+}
+
+/// Open proposals kept in the storage of a module, shared by the council and multisig modules.
+///
+/// `Hashes` lists the hashes of the open proposals, `Calls` holds their calls and
+/// `DetailsOf` their details, which hold the votes.
+pub struct OpenProposals<H, C, D, Hashes, Calls, DetailsOf>(rstd::marker::PhantomData<(H, C, D, Hashes, Calls, DetailsOf)>);
+
+impl<H, C, D, Hashes, Calls, DetailsOf> OpenProposals<H, C, D, Hashes, Calls, DetailsOf> where
+	H: Codec + Copy + PartialEq,
+	C: Codec,
+	D: Codec + ProposalVotes,
+	Hashes: StorageValue<Vec<H>, Query=Vec<H>>,
+	Calls: StorageMap<H, C, Query=Option<C>>,
+	DetailsOf: StorageMap<H, D, Query=Option<D>>,
+{
+	/// Open a proposal of `call`.
+	pub fn open(hash: H, call: C, details: D) -> Result {
+		ensure!(!Calls::exists(hash), "Duplicate proposal.");
+
+		Hashes::mutate(|hashes| hashes.push(hash));
+		Calls::insert(hash, call);
+		DetailsOf::insert(hash, details);
+		Ok(())
+	}
+
+	/// Add the approval of `who`.
+	pub fn approve(hash: H, who: D::AccountId) -> Result {
+		let mut details = DetailsOf::get(hash).ok_or("Proposal does not exist.")?;
+		details.votes_mut().approve(who)?;
+		DetailsOf::insert(hash, details);
+		Ok(())
+	}
+
+	/// Cancel a proposal on behalf of its proposer.
+	pub fn cancel(hash: H, who: &D::AccountId) -> Result {
+		let details = DetailsOf::get(hash).ok_or("Proposal does not exist.")?;
+		ensure!(details.votes().proposer == *who, "Only the proposer can cancel a proposal.");
+
+		Self::remove(hash);
+		Ok(())
+	}
+
+	/// Close a proposal and return its call.
+	pub fn take(hash: H) -> Option<C> {
+		let call = Calls::get(hash);
+		Self::remove(hash);
+		call
+	}
+
+	/// Close the proposals that expire at or before block `n` and return their hashes.
+	pub fn expire(n: &D::BlockNumber) -> Vec<H> {
+		let expired: Vec<H> = Hashes::get().into_iter()
+			.filter(|hash| DetailsOf::get(*hash).map_or(true, |details| details.votes().expiry <= *n))
+			.collect();
+		for hash in &expired {
+			Self::remove(*hash);
+		}
+		expired
+	}
+
+	/// Close a proposal.
+	pub fn remove(hash: H) {
+		Calls::remove(hash);
+		DetailsOf::remove(hash);
+		Hashes::mutate(|hashes| hashes.retain(|h| *h != hash));
+	}
+}
+
+/// Add the vote of `who` to a list of votes, unless it is already in it.
+pub fn add_vote<AccountId: PartialEq>(votes: &mut Vec<AccountId>, who: AccountId) -> Result {
+	ensure!(!votes.contains(&who), "You have already approved this proposal.");
+	votes.push(who);
+	Ok(())
+}
+
+/// Number of approvals given by current validators.
+///
+/// Approvals of validators that have been removed since do not count.
+pub fn current_approvals<T: validatorset::Trait>(approvals: &[T::AccountId]) -> u32 {
+	approvals.iter()
+		.filter(|v| <validatorset::Module<T>>::is_validator(v))
+		.count() as u32
+}
+
+/// Removal of the sudo key once the council takes over root-level calls.
+pub trait RemoveSudoKey {
+	/// Make the sudo key unusable.
+	fn remove_sudo_key() -> Result;
+}
+
+pub trait Trait: validatorset::Trait {
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+
+	/// A root-level call that validators can approve.
+	type Proposal: Parameter + Dispatchable<Origin=Self::Origin>;
+
+	/// Removes the sudo key when council mode is enabled.
+	type Sudo: RemoveSudoKey;
+}
+
+decl_storage! {
+	trait Store for Module<T: Trait> as Council {
+		/// Whether root-level calls go through validator approval instead of sudo.
+		Enabled get(enabled) config(): bool;
+		/// Number of validator approvals needed to execute a proposal.
+		/// Zero means all current validators.
+		Threshold get(threshold) config(): u32;
+		/// Set once the sudo key has been removed after enabling the council.
+		SudoRemoved get(sudo_removed): bool;
+		/// Number of blocks a proposal stays open.
+		ProposalLifetime get(proposal_lifetime) config(): T::BlockNumber = T::BlockNumber::sa(14400);
+
+		/// Hashes of the open proposals.
+		Proposals get(proposals): Vec<T::Hash>;
+		ProposalOf get(proposal_of): map T::Hash => Option<T::Proposal>;
+		VotesOf get(votes_of): map T::Hash => Option<Votes<T::AccountId, T::BlockNumber>>;
+	}
+}
+
+type Open<T> = OpenProposals<
+	<T as system::Trait>::Hash,
+	<T as Trait>::Proposal,
+	Votes<<T as system::Trait>::AccountId, <T as system::Trait>::BlockNumber>,
+	Proposals<T>,
+	ProposalOf<T>,
+	VotesOf<T>,
+>;
+
+decl_event!(
+  pub enum Event<T> where AccountId = <T as system::Trait>::AccountId, Hash = <T as system::Trait>::Hash {
+	  // A root-level call was proposed. First argument is the AccountId of proposer.
+	  Proposed(AccountId, Hash),
+
+	  // A proposal was approved by a validator.
+	  Approved(AccountId, Hash),
+
+	  // A proposal reached the threshold and was executed. The bool is true if it succeeded.
+	  Executed(Hash, bool),
+
+	  // A proposal was cancelled by its proposer.
+	  Cancelled(Hash),
+
+	  // A proposal expired without reaching the threshold.
+	  Expired(Hash),
+
+	  // Council mode was enabled.
+	  CouncilEnabled,
+
+	  // The sudo key was removed.
+	  SudoKeyRemoved,
+  }
+);
+
+decl_module! {
+	pub struct Module<T: Trait> for enum Call where origin: T::Origin {
+		fn deposit_event<T>() = default;
+
+		// One-time migration: once council mode is on, sudo is no longer needed.
+		fn on_initialize(_n: T::BlockNumber) {
+			if Self::enabled() && !Self::sudo_removed() {
+				Self::remove_sudo_key();
+			}
+		}
+
+		fn on_finalize(n: T::BlockNumber) {
+			for proposal_hash in Open::<T>::expire(&n) {
+				Self::deposit_event(RawEvent::Expired(proposal_hash));
+			}
+		}
+
+		/// Propose a root-level call.
+		///
+		/// Can only be called by an existing validator and counts as its approval.
+		pub fn propose(origin, proposal: Box<T::Proposal>) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(Self::enabled(), "Council mode is not enabled.");
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+
+			let proposal_hash = T::Hashing::hash_of(&proposal);
+			let expiry = <system::Module<T>>::block_number() + Self::proposal_lifetime();
+			Open::<T>::open(proposal_hash, *proposal, Votes::new(who.clone(), expiry))?;
+
+			Self::deposit_event(RawEvent::Proposed(who, proposal_hash));
+			Self::execute_if_approved(proposal_hash);
+			Ok(())
+		}
+
+		/// Approve an open proposal.
+		///
+		/// Can only be called by an existing validator.
+		/// The proposal is executed with root privileges once the threshold is reached.
+		pub fn approve(origin, proposal_hash: T::Hash) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+			Open::<T>::approve(proposal_hash, who.clone())?;
+
+			Self::deposit_event(RawEvent::Approved(who, proposal_hash));
+			Self::execute_if_approved(proposal_hash);
+			Ok(())
+		}
+
+		/// Cancel an open proposal.
+		///
+		/// Can only be called by the proposer.
+		pub fn cancel(origin, proposal_hash: T::Hash) -> Result {
+			let who = ensure_signed(origin)?;
+			Open::<T>::cancel(proposal_hash, &who)?;
+
+			Self::deposit_event(RawEvent::Cancelled(proposal_hash));
+			Ok(())
+		}
+
+		/// Turn council mode on using root/sudo privileges.
+		///
+		/// The sudo key is removed at the beginning of the next block.
+		pub fn enable_council(threshold: u32) -> Result {
+			ensure!(!Self::enabled(), "Council mode is already enabled.");
+
+			<Threshold<T>>::put(threshold);
+			<Enabled<T>>::put(true);
+
+			Self::deposit_event(RawEvent::CouncilEnabled);
+			Ok(())
+		}
+
+		/// Change the approval threshold.
+		///
+		/// Has to be approved by the council itself.
+		pub fn set_threshold(threshold: u32) -> Result {
+			<Threshold<T>>::put(threshold);
+			Ok(())
+		}
+
+		/// Set the number of blocks a proposal stays open.
+		///
+		/// Has to be approved by the council itself.
+		pub fn set_proposal_lifetime(lifetime: T::BlockNumber) -> Result {
+			<ProposalLifetime<T>>::put(lifetime);
+			Ok(())
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	/// Number of approvals needed with the current validator set.
+	pub fn required_approvals() -> u32 {
+		let count = <session::Module<T>>::validator_count();
+		match Self::threshold() {
+			0 => count,
+			threshold => rstd::cmp::min(threshold, count),
+		}
+	}
+
+	/// Whether enough current validators are among `approvals`.
+	pub fn is_approved_by(approvals: &[T::AccountId]) -> bool {
+		current_approvals::<T>(approvals) >= Self::required_approvals()
+	}
+
+	// Executes a proposal with root privileges if enough current validators approved it.
+	fn execute_if_approved(proposal_hash: T::Hash) {
+		let approvals = Self::votes_of(proposal_hash).map_or(0, |votes| current_approvals::<T>(&votes.approvals));
+		if approvals < Self::required_approvals() {
+			return;
+		}
+
+		if let Some(proposal) = Open::<T>::take(proposal_hash) {
+			let ok = proposal.dispatch(system::RawOrigin::Root.into()).is_ok();
+			Self::deposit_event(RawEvent::Executed(proposal_hash, ok));
+		}
+	}
+
+	// Retried in the next block if the sudo module refuses the change.
+	fn remove_sudo_key() {
+		if T::Sudo::remove_sudo_key().is_err() {
+			return;
+		}
+		<SudoRemoved<T>>::put(true);
+
+		Self::deposit_event(RawEvent::SudoKeyRemoved);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use runtime_primitives::traits::{OnInitialize, OnFinalize};
+	use support::{assert_ok, assert_noop};
+	use crate::mock::{new_test_ext, account, signed, events, ROOT};
+	use crate::{Event, Call, ValidatorSetCall, AccountId, Council, Sudo, ValidatorSet};
+
+	fn set_delay(delay: u64) -> Box<Call> {
+		Box::new(Call::ValidatorSet(ValidatorSetCall::set_emergency_delay(delay)))
+	}
+
+	fn hash_of(call: &Box<Call>) -> crate::Hash {
+		<crate::Runtime as system::Trait>::Hashing::hash_of(call)
+	}
+
+	#[test]
+	fn enabling_council_removes_sudo_key() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_eq!(Sudo::key(), account(ROOT));
+			assert_ok!(Council::enable_council(2));
+
+			<Council as OnInitialize<u64>>::on_initialize(1);
+			assert!(Council::sudo_removed());
+			assert_eq!(Sudo::key(), AccountId::default());
+		});
+	}
+
+	#[test]
+	fn proposal_executes_at_threshold() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_ok!(Council::enable_council(2));
+			let call = set_delay(7);
+			let proposal_hash = hash_of(&call);
+
+			assert_ok!(Council::propose(signed(1), call));
+			assert_noop!(Council::approve(signed(1), proposal_hash), "You have already approved this proposal.");
+			assert_eq!(ValidatorSet::emergency_delay(), 0);
+
+			assert_ok!(Council::approve(signed(2), proposal_hash));
+			assert_eq!(ValidatorSet::emergency_delay(), 7);
+			assert!(Council::votes_of(proposal_hash).is_none());
+			assert!(events().contains(&Event::council(RawEvent::Executed(proposal_hash, true))));
+		});
+	}
+
+	#[test]
+	fn only_proposer_can_cancel() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_ok!(Council::enable_council(0));
+			let call = set_delay(7);
+			let proposal_hash = hash_of(&call);
+			assert_ok!(Council::propose(signed(1), call));
+
+			assert_noop!(Council::cancel(signed(2), proposal_hash), "Only the proposer can cancel a proposal.");
+			assert_ok!(Council::cancel(signed(1), proposal_hash));
+			assert!(Council::proposals().is_empty());
+			assert_noop!(Council::approve(signed(2), proposal_hash), "Proposal does not exist.");
+		});
+	}
+
+	#[test]
+	fn proposal_expires_after_lifetime() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_ok!(Council::enable_council(0));
+			let call = set_delay(7);
+			let proposal_hash = hash_of(&call);
+			assert_ok!(Council::propose(signed(1), call));
+
+			<Council as OnFinalize<u64>>::on_finalize(99);
+			assert!(Council::votes_of(proposal_hash).is_some());
+
+			<Council as OnFinalize<u64>>::on_finalize(100);
+			assert!(Council::votes_of(proposal_hash).is_none());
+			assert!(events().contains(&Event::council(RawEvent::Expired(proposal_hash))));
+		});
+	}
+}
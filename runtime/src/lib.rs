@@ -14,11 +14,12 @@ use primitives::bytes;
 use primitives::{ed25519, sr25519, OpaqueMetadata};
 use runtime_primitives::{
 	ApplyResult, transaction_validity::TransactionValidity, generic, create_runtime_str,
-	traits::{self, NumberFor, BlakeTwo256, Block as BlockT, StaticLookup, Verify}
+	traits::{self, NumberFor, BlakeTwo256, Block as BlockT, DigestFor, Dispatchable, StaticLookup, Verify}
 };
+use fg_primitives::ScheduledChange;
 use client::{
 	block_builder::api::{CheckInherentsResult, InherentData, self as block_builder_api},
-	runtime_api, impl_runtime_apis
+	runtime_api, impl_runtime_apis, decl_runtime_apis
 };
 use version::RuntimeVersion;
 #[cfg(feature = "std")]
@@ -30,9 +31,14 @@ pub use runtime_primitives::BuildStorage;
 pub use consensus::Call as ConsensusCall;
 pub use timestamp::Call as TimestampCall;
 pub use balances::Call as BalancesCall;
+pub use sudo::Call as SudoCall;
+pub use validatorset::Call as ValidatorSetCall;
+pub use misbehavior::{Call as MisbehaviorCall, EquivocationProof};
 pub use runtime_primitives::{Permill, Perbill};
 pub use timestamp::BlockPeriod;
 pub use support::{StorageValue, construct_runtime};
+pub use validatorset::ValidatorStats;
+pub use upgrade::code_version;
 
 /// The type that is used for identifying authorities.
 pub type AuthorityId = <AuthoritySignature as Verify>::Signer;
@@ -56,6 +62,31 @@ pub type BlockNumber = u64;
 pub type Nonce = u64;
 
 mod validatorset;
+mod council;
+mod multisig;
+mod upgrade;
+mod allowlist;
+mod ratelimit;
+mod nodeauth;
+mod misbehavior;
+#[cfg(test)]
+mod mock;
+
+decl_runtime_apis! {
+	/// Queries of the `ValidatorSet` module state.
+	pub trait ValidatorSetApi {
+		/// Authorship counters of the given validator.
+		fn validator_stats(account: AccountId) -> ValidatorStats;
+		/// Authorship counters of all current validators.
+		fn all_validator_stats() -> Vec<(AccountId, ValidatorStats)>;
+	}
+
+	/// Queries of the `NodeAuth` module state.
+	pub trait NodeAuthApi {
+		/// Base58 encoded `PeerId`s nodes should accept, or `None` if any peer is accepted.
+		fn peer_allowlist() -> Option<Vec<Vec<u8>>>;
+	}
+}
 
 /// Opaque types. These are used by the CLI to instantiate machinery that don't need to know
 /// the specifics of the runtime. They can then be made to be agnostic over specific formats
@@ -89,16 +120,29 @@ pub mod opaque {
 	pub type SessionKey = AuthorityId;
 }
 
+/// `spec_version` of this runtime.
+pub const SPEC_VERSION: u32 = 4;
+
 /// This runtime version.
 pub const VERSION: RuntimeVersion = RuntimeVersion {
 	spec_name: create_runtime_str!("substrate-poa"),
 	impl_name: create_runtime_str!("substrate-poa"),
 	authoring_version: 3,
-	spec_version: 3,
+	spec_version: SPEC_VERSION,
 	impl_version: 0,
 	apis: RUNTIME_API_VERSIONS,
 };
 
+/// `spec_version` (little endian) and `spec_name` of this runtime, embedded in the wasm blob
+/// as a custom section so that `Upgrade::upload_code` can check the version of new code.
+#[cfg(not(feature = "std"))]
+#[link_section = "poa_version"]
+#[used]
+static VERSION_SECTION: [u8; 17] = [
+	SPEC_VERSION as u8, (SPEC_VERSION >> 8) as u8, (SPEC_VERSION >> 16) as u8, (SPEC_VERSION >> 24) as u8,
+	b's', b'u', b'b', b's', b't', b'r', b'a', b't', b'e', b'-', b'p', b'o', b'a',
+];
+
 /// The version infromation used to identify this runtime when compiled natively.
 #[cfg(feature = "std")]
 pub fn native_version() -> NativeVersion {
@@ -149,7 +193,16 @@ impl consensus::Trait for Runtime {
 
 impl session::Trait for Runtime {
 	type ConvertAccountIdToSessionKey = ();
-	type OnSessionChange = ();
+	type OnSessionChange = ValidatorSet;
+	type Event = Event;
+}
+
+impl grandpa::Trait for Runtime {
+	/// The identifier we use to refer to authorities.
+	type SessionKey = AuthorityId;
+	/// The ubiquitous log type.
+	type Log = Log;
+	/// The ubiquitous event type.
 	type Event = Event;
 }
 
@@ -196,6 +249,65 @@ impl validatorset::Trait for Runtime {
 	type Event = Event;
 }
 
+impl council::RemoveSudoKey for Runtime {
+	// `srml-sudo` only lets the key holder change the key, so the current key hands it
+	// over to the default account, which nobody controls.
+	fn remove_sudo_key() -> support::dispatch::Result {
+		sudo::Call::<Runtime>::set_key(Indices::unlookup(AccountId::default()))
+			.dispatch(system::RawOrigin::Signed(Sudo::key()).into())
+	}
+}
+
+impl council::Trait for Runtime {
+	type Event = Event;
+	type Proposal = Call;
+	type Sudo = Runtime;
+}
+
+impl multisig::Trait for Runtime {
+	type Event = Event;
+	type Origin = Origin;
+	type Proposal = Call;
+}
+
+impl multisig::EnsureValidators<Origin> for Runtime {
+	fn ensure_root_or_majority(o: Origin) -> support::dispatch::Result {
+		if system::ensure_root(o.clone()).is_ok() {
+			return Ok(());
+		}
+		multisig::ensure_majority(o.into())
+	}
+}
+
+impl upgrade::CurrentVersion for Runtime {
+	fn current_version() -> RuntimeVersion {
+		VERSION
+	}
+}
+
+impl upgrade::Trait for Runtime {
+	type Event = Event;
+	type Version = Runtime;
+}
+
+impl allowlist::Trait for Runtime {
+	type Event = Event;
+}
+
+impl ratelimit::Trait for Runtime {
+	type Event = Event;
+	type Validators = Runtime;
+}
+
+impl nodeauth::Trait for Runtime {
+	type Event = Event;
+}
+
+impl misbehavior::Trait for Runtime {
+	type Signature = AuthoritySignature;
+	type Event = Event;
+}
+
 construct_runtime!(
 	pub enum Runtime with Log(InternalLog: DigestItem<Hash, AuthorityId, AuthoritySignature>) where
 		Block = Block,
@@ -206,16 +318,25 @@ construct_runtime!(
 		Timestamp: timestamp::{Module, Call, Storage, Config<T>, Inherent},
 		Consensus: consensus::{Module, Call, Storage, Config<T>, Log(AuthoritiesChange), Inherent},
 		Aura: aura::{Module},
+		Grandpa: grandpa::{Module, Call, Storage, Config<T>, Log(), Event<T>},
 		Indices: indices,
 		Session: session,
 		Balances: balances,
 		Sudo: sudo,
 		ValidatorSet: validatorset::{Module, Call, Storage, Config<T>, Event<T>},
+		Council: council::{Module, Call, Storage, Config<T>, Event<T>},
+		Multisig: multisig::{Module, Call, Storage, Config<T>, Event<T>, Origin},
+		Upgrade: upgrade::{Module, Call, Storage, Config<T>, Event<T>},
+		Allowlist: allowlist::{Module, Call, Storage, Config<T>, Event<T>},
+		RateLimit: ratelimit::{Module, Call, Storage, Config<T>, Event<T>},
+		NodeAuth: nodeauth::{Module, Call, Storage, Config<T>, Event<T>},
+		Misbehavior: misbehavior::{Module, Call, Storage, Config, Event<T>},
 	}
 );
 
 /// The type used as a helper for interpreting the sender of transactions.
-type Context = system::ChainContext<Runtime>;
+/// Rejects senders that are not permitted by the `Allowlist` module.
+type Context = allowlist::ChainContext<Runtime>;
 /// The address format for describing accounts.
 type Address = <Indices as StaticLookup>::Source;
 /// Block header type as expected by this runtime.
@@ -228,8 +349,10 @@ pub type BlockId = generic::BlockId<Block>;
 pub type UncheckedExtrinsic = generic::UncheckedMortalCompactExtrinsic<Address, Nonce, Call, AccountSignature>;
 /// Extrinsic type that has already been checked.
 pub type CheckedExtrinsic = generic::CheckedExtrinsic<AccountId, Nonce, Call>;
+/// Transaction payment: balances fees, or rate limits in fee-less mode.
+type Payment = ratelimit::Payment<Runtime, Balances>;
 /// Executive: handles dispatch to the various modules.
-pub type Executive = executive::Executive<Runtime, Block, Context, Balances, AllModules>;
+pub type Executive = executive::Executive<Runtime, Block, Context, Payment, AllModules>;
 
 // Implement our runtime API endpoints. This is just a bunch of proxying.
 impl_runtime_apis! {
@@ -281,7 +404,19 @@ impl_runtime_apis! {
 
 	impl runtime_api::TaggedTransactionQueue<Block> for Runtime {
 		fn validate_transaction(tx: <Block as BlockT>::Extrinsic) -> TransactionValidity {
-			Executive::validate_transaction(tx)
+			// The executive rejects unsigned transactions, except for misbehavior reports.
+			if tx.signature.is_none() {
+				if let Call::Misbehavior(ref call) = tx.function {
+					return Misbehavior::validate_unsigned(call);
+				}
+			}
+
+			let sender = tx.signature.as_ref().and_then(|s| Indices::lookup(s.0.clone()).ok());
+			let validity = Executive::validate_transaction(tx);
+			match sender {
+				Some(who) => RateLimit::adjust_validity(&who, validity),
+				None => validity,
+			}
 		}
 	}
 
@@ -297,9 +432,59 @@ impl_runtime_apis! {
 		}
 	}
 
+	impl fg_primitives::GrandpaApi<Block> for Runtime {
+		fn grandpa_pending_change(digest: &DigestFor<Block>)
+			-> Option<ScheduledChange<NumberFor<Block>>>
+		{
+			for log in digest.logs.iter().filter_map(|l| match l {
+				Log(InternalLog::grandpa(grandpa_signal)) => Some(grandpa_signal),
+				_ => None
+			}) {
+				if let Some(change) = Grandpa::scrape_digest_change(log) {
+					return Some(change);
+				}
+			}
+			None
+		}
+
+		fn grandpa_forced_change(digest: &DigestFor<Block>)
+			-> Option<(NumberFor<Block>, ScheduledChange<NumberFor<Block>>)>
+		{
+			for log in digest.logs.iter().filter_map(|l| match l {
+				Log(InternalLog::grandpa(grandpa_signal)) => Some(grandpa_signal),
+				_ => None
+			}) {
+				if let Some(change) = Grandpa::scrape_digest_forced_change(log) {
+					return Some(change);
+				}
+			}
+			None
+		}
+
+		fn grandpa_authorities() -> Vec<(AuthorityId, u64)> {
+			Grandpa::grandpa_authorities()
+		}
+	}
+
 	impl consensus_authorities::AuthoritiesApi<Block> for Runtime {
 		fn authorities() -> Vec<AuthorityId> {
 			Consensus::authorities()
 		}
 	}
+
+	impl self::ValidatorSetApi<Block> for Runtime {
+		fn validator_stats(account: AccountId) -> ValidatorStats {
+			ValidatorSet::authorship_stats(account)
+		}
+
+		fn all_validator_stats() -> Vec<(AccountId, ValidatorStats)> {
+			ValidatorSet::all_authorship_stats()
+		}
+	}
+
+	impl self::NodeAuthApi<Block> for Runtime {
+		fn peer_allowlist() -> Option<Vec<Vec<u8>>> {
+			NodeAuth::peer_allowlist()
+		}
+	}
 }
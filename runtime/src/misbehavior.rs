@@ -0,0 +1,239 @@
+use support::{decl_module, decl_storage, decl_event, StorageValue, ensure, dispatch::Result, Parameter};
+use rstd::prelude::*;
+use system::ensure_inherent;
+use runtime_primitives::traits::Verify;
+use runtime_primitives::transaction_validity::TransactionValidity;
+use runtime_primitives::ApplyError;
+use parity_codec::{Encode, Decode};
+use crate::validatorset;
+
+/// Two different blocks signed by the same authority for the same Aura slot.
+///
+/// Aura signs `(slot, pre_hash)` where `pre_hash` is the hash of the header without its seal,
+/// so the signatures alone prove the equivocation.
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct EquivocationProof<SessionKey, Signature, Hash> {
+	/// Session key of the offender.
+	pub authority: SessionKey,
+	/// Slot of both blocks.
+	pub slot: u64,
+	/// Pre-seal hash and seal signature of the first block.
+	pub first: (Hash, Signature),
+	/// Pre-seal hash and seal signature of the second block.
+	pub second: (Hash, Signature),
+}
+
+pub type EquivocationProofFor<T> = EquivocationProof<
+	<T as consensus::Trait>::SessionKey,
+	<T as Trait>::Signature,
+	<T as system::Trait>::Hash,
+>;
+
+pub trait Trait: validatorset::Trait {
+	/// Signature of block seals.
+	type Signature: Verify<Signer = <Self as consensus::Trait>::SessionKey> + Parameter;
+
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+}
+
+decl_storage! {
+	trait Store for Module<T: Trait> as Misbehavior {
+		/// Whether a removal proposal is opened for validators that equivocate.
+		ProposeRemoval get(propose_removal) config(): bool;
+	}
+}
+
+decl_event!(
+  pub enum Event<T> where AccountId = <T as system::Trait>::AccountId {
+	  // Equivocation of the validator in the given slot reported.
+	  EquivocationReported(AccountId, u64),
+
+	  // Automatic removal proposals of equivocating validators turned on or off.
+	  ProposeRemovalSet(bool),
+  }
+);
+
+decl_module! {
+	pub struct Module<T: Trait> for enum Call where origin: T::Origin {
+		fn deposit_event<T>() = default;
+
+		/// Report an equivocation of a current validator.
+		///
+		/// Submitted as an unsigned extrinsic by the nodes that notice it.
+		pub fn report_equivocation(origin, proof: EquivocationProofFor<T>) -> Result {
+			ensure_inherent(origin)?;
+
+			// The pool checked the proof against an earlier state, so it is checked again.
+			let offender = Self::check_proof(&proof)?;
+			<validatorset::Module<T>>::ensure_new_equivocation(&offender, proof.slot)?;
+
+			<validatorset::Module<T>>::note_equivocation(&offender, proof.slot);
+			if Self::propose_removal() {
+				<validatorset::Module<T>>::open_removal_proposal(offender.clone(), proof.authority);
+			}
+
+			Self::deposit_event(RawEvent::EquivocationReported(offender, proof.slot));
+			Ok(())
+		}
+
+		/// Turn automatic removal proposals of equivocating validators on or off using root privileges.
+		pub fn set_propose_removal(enabled: bool) -> Result {
+			<ProposeRemoval<T>>::put(enabled);
+
+			Self::deposit_event(RawEvent::ProposeRemovalSet(enabled));
+			Ok(())
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	/// Pool validity of an unsigned call of this module.
+	///
+	/// Only valid equivocation reports of current authorities that can still be recorded are accepted.
+	pub fn validate_unsigned(call: &Call<T>) -> TransactionValidity {
+		let proof = match call {
+			Call::report_equivocation(proof) => proof,
+			_ => return TransactionValidity::Invalid(ApplyError::BadSignature as i8),
+		};
+
+		let offender = match Self::check_proof(proof) {
+			Ok(offender) => offender,
+			Err(_) => return TransactionValidity::Invalid(ApplyError::BadSignature as i8),
+		};
+		if <validatorset::Module<T>>::ensure_new_equivocation(&offender, proof.slot).is_err() {
+			return TransactionValidity::Invalid(ApplyError::Stale as i8);
+		}
+
+		TransactionValidity::Valid {
+			priority: 1 << 20,
+			requires: Vec::new(),
+			provides: vec![(&b"equivocation"[..], &proof.authority, proof.slot).encode()],
+			longevity: 64,
+		}
+	}
+
+	// Checks the proof and returns the account of the offender.
+	fn check_proof(proof: &EquivocationProofFor<T>) -> rstd::result::Result<T::AccountId, &'static str> {
+		ensure!(proof.first.0 != proof.second.0, "Both blocks are the same.");
+
+		for (hash, signature) in [&proof.first, &proof.second].iter() {
+			let payload = (proof.slot, hash).encode();
+			ensure!(signature.verify(&payload[..], &proof.authority), "Invalid seal signature.");
+		}
+
+		ensure!(<consensus::Module<T>>::authorities().contains(&proof.authority), "Not a current authority.");
+		<validatorset::Module<T>>::validator_of(&proof.authority).ok_or("Not a current validator.")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use support::{assert_ok, assert_noop};
+	use primitives::{H256, Pair};
+	use runtime_primitives::traits::OnInitialize;
+	use crate::mock::{new_test_ext, account, session_key, session_pair, events};
+	use crate::{Event, Misbehavior, Timestamp, ValidatorSet};
+
+	// Proof that the validator with the session key `n` sealed both blocks in `slot`.
+	fn proof(n: u8, slot: u64, first: H256, second: H256) -> EquivocationProofFor<crate::Runtime> {
+		let seal = |hash: H256| (hash, session_pair(n).sign(&(slot, hash).encode()[..]));
+		EquivocationProof { authority: session_key(n), slot, first: seal(first), second: seal(second) }
+	}
+
+	fn report(proof: EquivocationProofFor<crate::Runtime>) -> Result {
+		Misbehavior::report_equivocation(system::RawOrigin::Inherent.into(), proof)
+	}
+
+	#[test]
+	fn equivocation_is_recorded() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_ok!(report(proof(2, 7, H256::from([1; 32]), H256::from([2; 32]))));
+
+			assert_eq!(ValidatorSet::equivocations(account(2)), vec![7]);
+			assert!(events().contains(&Event::misbehavior(RawEvent::EquivocationReported(account(2), 7))));
+			assert!(ValidatorSet::removal_keys().is_empty());
+		});
+	}
+
+	#[test]
+	fn invalid_proofs_are_rejected() {
+		with_externalities(&mut new_test_ext(3), || {
+			let (a, b) = (H256::from([1; 32]), H256::from([2; 32]));
+
+			assert_noop!(report(proof(2, 7, a, a)), "Both blocks are the same.");
+
+			let mut forged = proof(2, 7, a, b);
+			forged.second.1 = session_pair(2).sign(&(8u64, b).encode()[..]);
+			assert_noop!(report(forged), "Invalid seal signature.");
+
+			let mut other_signer = proof(2, 7, a, b);
+			other_signer.authority = session_key(3);
+			assert_noop!(report(other_signer), "Invalid seal signature.");
+
+			assert_noop!(report(proof(9, 7, a, b)), "Not a current authority.");
+		});
+	}
+
+	#[test]
+	fn equivocations_are_checked_against_the_window() {
+		with_externalities(&mut new_test_ext(3), || {
+			let (a, b) = (H256::from([1; 32]), H256::from([2; 32]));
+			// Slots last 6 seconds.
+			Timestamp::set_timestamp((validatorset::EQUIVOCATION_WINDOW + 100) * 6);
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(1);
+
+			assert_noop!(report(proof(2, 99, a, b)), "Equivocation is too old.");
+			assert_ok!(report(proof(2, 100, a, b)));
+			assert_noop!(report(proof(2, 100, a, b)), "Equivocation already reported.");
+
+			for slot in 101..100 + validatorset::MAX_EQUIVOCATIONS as u64 {
+				assert_ok!(report(proof(2, slot, a, b)));
+			}
+			let slot = 100 + validatorset::MAX_EQUIVOCATIONS as u64;
+			assert_noop!(report(proof(2, slot, a, b)), "Too many equivocations of the validator recorded.");
+		});
+	}
+
+	#[test]
+	fn pool_only_accepts_new_valid_reports() {
+		with_externalities(&mut new_test_ext(3), || {
+			let (a, b) = (H256::from([1; 32]), H256::from([2; 32]));
+			let call = |proof| Call::<crate::Runtime>::report_equivocation(proof);
+
+			match Misbehavior::validate_unsigned(&call(proof(2, 7, a, b))) {
+				TransactionValidity::Valid { provides, .. } =>
+					assert_eq!(provides, vec![(&b"equivocation"[..], session_key(2), 7u64).encode()]),
+				_ => panic!("valid report rejected"),
+			}
+			assert_eq!(
+				Misbehavior::validate_unsigned(&call(proof(2, 7, a, a))),
+				TransactionValidity::Invalid(ApplyError::BadSignature as i8)
+			);
+			assert_eq!(
+				Misbehavior::validate_unsigned(&call(proof(9, 7, a, b))),
+				TransactionValidity::Invalid(ApplyError::BadSignature as i8)
+			);
+
+			assert_ok!(report(proof(2, 7, a, b)));
+			assert_eq!(
+				Misbehavior::validate_unsigned(&call(proof(2, 7, a, b))),
+				TransactionValidity::Invalid(ApplyError::Stale as i8)
+			);
+		});
+	}
+
+	#[test]
+	fn reports_open_removal_proposals_when_enabled() {
+		with_externalities(&mut new_test_ext(3), || {
+			let (a, b) = (H256::from([1; 32]), H256::from([2; 32]));
+			assert_ok!(Misbehavior::set_propose_removal(true));
+
+			assert_ok!(report(proof(2, 7, a, b)));
+			assert_ok!(report(proof(2, 8, a, b)));
+			assert_eq!(ValidatorSet::removal_keys(), vec![(account(2), session_key(2))]);
+		});
+	}
+}
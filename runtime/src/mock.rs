@@ -0,0 +1,114 @@
+//! Test externalities of the runtime, shared by the module tests.
+
+use primitives::{ed25519, sr25519, Blake2Hasher, Pair};
+use runtime_io::TestExternalities;
+use runtime_primitives::BuildStorage;
+use super::{
+	AccountId, AuthorityId, Event, GenesisConfig, Origin, System,
+	ConsensusConfig, TimestampConfig, GrandpaConfig, IndicesConfig, SessionConfig, BalancesConfig, SudoConfig,
+	ValidatorSetConfig, CouncilConfig, MultisigConfig, UpgradeConfig, AllowlistConfig, RateLimitConfig,
+	NodeAuthConfig, MisbehaviorConfig,
+};
+
+/// Account of the sudo key, which is not a validator.
+pub const ROOT: u8 = 100;
+
+/// Account with the given number.
+pub fn account(n: u8) -> AccountId {
+	sr25519::Public::from_raw([n; 32])
+}
+
+/// Origin signed by the account with the given number.
+pub fn signed(n: u8) -> Origin {
+	system::RawOrigin::Signed(account(n)).into()
+}
+
+/// Key pair of the session key with the given number.
+pub fn session_pair(n: u8) -> ed25519::Pair {
+	ed25519::Pair::from_seed(&[n; 32])
+}
+
+/// Session key with the given number.
+pub fn session_key(n: u8) -> AuthorityId {
+	session_pair(n).public()
+}
+
+/// Externalities with validators `1..=count`, each using the session key of the same number.
+///
+/// Slots last 6 seconds.
+pub fn new_test_ext(count: u8) -> TestExternalities<Blake2Hasher> {
+	let authorities: Vec<(AccountId, AuthorityId)> = (1..=count).map(|n| (account(n), session_key(n))).collect();
+	let mut endowed: Vec<AccountId> = authorities.iter().map(|x| x.0.clone()).collect();
+	endowed.push(account(ROOT));
+
+	GenesisConfig {
+		consensus: Some(ConsensusConfig {
+			code: vec![],
+			authorities: authorities.iter().map(|x| x.1.clone()).collect(),
+		}),
+		system: None,
+		timestamp: Some(TimestampConfig {
+			minimum_period: 3,
+		}),
+		grandpa: Some(GrandpaConfig {
+			authorities: authorities.iter().map(|x| (x.1.clone(), 1)).collect(),
+		}),
+		indices: Some(IndicesConfig {
+			ids: endowed.clone(),
+		}),
+		session: Some(SessionConfig {
+			validators: authorities.iter().map(|x| x.0.clone()).collect(),
+			session_length: 10,
+			keys: authorities.clone(),
+		}),
+		balances: Some(BalancesConfig {
+			transaction_base_fee: 0,
+			transaction_byte_fee: 0,
+			existential_deposit: 0,
+			transfer_fee: 0,
+			creation_fee: 0,
+			balances: endowed.iter().map(|x| (x.clone(), 1 << 60)).collect(),
+			vesting: vec![],
+		}),
+		sudo: Some(SudoConfig {
+			key: account(ROOT),
+		}),
+		validatorset: Some(ValidatorSetConfig {
+			validators: authorities,
+			emergency_delay: 0,
+		}),
+		council: Some(CouncilConfig {
+			enabled: false,
+			threshold: 0,
+			proposal_lifetime: 100,
+		}),
+		multisig: Some(MultisigConfig {
+			proposal_lifetime: 100,
+		}),
+		upgrade: Some(UpgradeConfig {
+			upgrade_delay: 10,
+		}),
+		allowlist: Some(AllowlistConfig {
+			enabled: false,
+			allowed: vec![],
+		}),
+		ratelimit: Some(RateLimitConfig {
+			enabled: false,
+			feeless: false,
+			max_transactions: 10,
+			window_length: 1,
+		}),
+		nodeauth: Some(NodeAuthConfig {
+			enabled: false,
+			authorized_peers: vec![],
+		}),
+		misbehavior: Some(MisbehaviorConfig {
+			propose_removal: false,
+		}),
+	}.build_storage().unwrap().0.into()
+}
+
+/// Events deposited so far, without their phase.
+pub fn events() -> Vec<Event> {
+	System::events().into_iter().map(|record| record.event).collect()
+}
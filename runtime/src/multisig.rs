@@ -0,0 +1,309 @@
+use support::{decl_module, decl_storage, decl_event, StorageValue, ensure, Parameter, dispatch::Result};
+use rstd::prelude::*;
+use system::{ensure_signed};
+use runtime_primitives::traits::{As, Hash, Dispatchable};
+use parity_codec::{Encode, Decode};
+use crate::{validatorset, council::{self, OpenProposals, ProposalVotes, Votes}};
+
+/// Origin of calls dispatched on behalf of the validators.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum Origin {
+	/// Approved by the first number of validators out of the second number.
+	Validators(u32, u32),
+}
+
+/// Check of the origin of calls that root or a majority of the validators may make.
+///
+/// Implemented by the runtime, which knows the outer origin type.
+pub trait EnsureValidators<OuterOrigin> {
+	/// Ensure `o` is root, or a proposal approved by more than half of the validators.
+	fn ensure_root_or_majority(o: OuterOrigin) -> Result;
+}
+
+/// Ensure `o` is the `Validators` origin of a proposal approved by more than half of the validators.
+pub fn ensure_majority(o: Option<Origin>) -> Result {
+	match o {
+		Some(Origin::Validators(approvals, count)) if approvals * 2 > count => Ok(()),
+		Some(_) => Err("Not approved by a majority of the validators."),
+		None => Err("bad origin: expected to be root or a majority of the validators"),
+	}
+}
+
+/// Details of an open proposal.
+#[derive(Encode, Decode, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct ProposalDetails<AccountId, BlockNumber> {
+	/// Proposer, approvals and expiry.
+	pub votes: Votes<AccountId, BlockNumber>,
+	/// Number of approvals needed to dispatch the call with the `Validators` origin.
+	pub threshold: u32,
+	/// Dispatch with root instead of the `Validators` origin, at the council threshold.
+	pub as_root: bool,
+}
+
+impl<AccountId: PartialEq + Clone, BlockNumber: PartialOrd> ProposalVotes for ProposalDetails<AccountId, BlockNumber> {
+	type AccountId = AccountId;
+	type BlockNumber = BlockNumber;
+
+	fn votes(&self) -> &Votes<AccountId, BlockNumber> {
+		&self.votes
+	}
+
+	fn votes_mut(&mut self) -> &mut Votes<AccountId, BlockNumber> {
+		&mut self.votes
+	}
+}
+
+pub trait Trait: council::Trait {
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+
+	/// The outer origin type.
+	type Origin: From<Origin> + From<system::RawOrigin<Self::AccountId>>;
+
+	/// A call that validators can approve.
+	type Proposal: Parameter + Dispatchable<Origin=<Self as Trait>::Origin>;
+}
+
+decl_storage! {
+	trait Store for Module<T: Trait> as Multisig {
+		/// Number of blocks a proposal stays open.
+		ProposalLifetime get(proposal_lifetime) config(): T::BlockNumber = T::BlockNumber::sa(14400);
+
+		/// Hashes of the open proposals.
+		Proposals get(proposals): Vec<T::Hash>;
+		ProposalOf get(proposal_of): map T::Hash => Option<<T as Trait>::Proposal>;
+		DetailsOf get(details_of): map T::Hash => Option<ProposalDetails<T::AccountId, T::BlockNumber>>;
+	}
+	extra_genesis_skip_phantom_data_field;
+}
+
+type Open<T> = OpenProposals<
+	<T as system::Trait>::Hash,
+	<T as Trait>::Proposal,
+	ProposalDetails<<T as system::Trait>::AccountId, <T as system::Trait>::BlockNumber>,
+	Proposals<T>,
+	ProposalOf<T>,
+	DetailsOf<T>,
+>;
+
+decl_event!(
+  pub enum Event<T> where AccountId = <T as system::Trait>::AccountId, Hash = <T as system::Trait>::Hash {
+	  // A call was proposed. First argument is the AccountId of proposer.
+	  Proposed(AccountId, Hash, u32),
+
+	  // A proposal was approved by a validator.
+	  Approved(AccountId, Hash),
+
+	  // A proposal reached its threshold and was dispatched. The bool is true if it succeeded.
+	  Executed(Hash, bool),
+
+	  // A proposal was cancelled by its proposer.
+	  Cancelled(Hash),
+
+	  // A proposal expired without reaching its threshold.
+	  Expired(Hash),
+  }
+);
+
+decl_module! {
+	pub struct Module<T: Trait> for enum Call where origin: <T as system::Trait>::Origin {
+		fn deposit_event<T>() = default;
+
+		fn on_finalize(n: T::BlockNumber) {
+			for proposal_hash in Open::<T>::expire(&n) {
+				Self::deposit_event(RawEvent::Expired(proposal_hash));
+			}
+		}
+
+		/// Propose a call to be dispatched once `threshold` validators approve it.
+		///
+		/// Can only be called by an existing validator and counts as its approval.
+		/// The call is dispatched with the `Validators` origin, which the calls checked
+		/// with `EnsureValidators` accept once more than half of the validators approved.
+		/// Root proposals are only possible in council mode and need the council
+		/// threshold instead of `threshold`, so they cannot bypass sudo or the council.
+		pub fn propose(origin, threshold: u32, as_root: bool, proposal: Box<<T as Trait>::Proposal>) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+
+			if as_root {
+				ensure!(<council::Module<T>>::enabled(), "Root proposals need council mode.");
+			} else {
+				let count = <session::Module<T>>::validator_count();
+				ensure!(threshold >= 1 && threshold <= count, "Threshold out of range.");
+			}
+
+			let proposal_hash = T::Hashing::hash_of(&proposal);
+			let expiry = <system::Module<T>>::block_number() + Self::proposal_lifetime();
+			let details = ProposalDetails { votes: Votes::new(who.clone(), expiry), threshold, as_root };
+			Open::<T>::open(proposal_hash, *proposal, details)?;
+
+			Self::deposit_event(RawEvent::Proposed(who, proposal_hash, threshold));
+			Self::execute_if_approved(proposal_hash);
+			Ok(())
+		}
+
+		/// Approve an open proposal.
+		///
+		/// Can only be called by an existing validator.
+		pub fn approve(origin, proposal_hash: T::Hash) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+			Open::<T>::approve(proposal_hash, who.clone())?;
+
+			Self::deposit_event(RawEvent::Approved(who, proposal_hash));
+			Self::execute_if_approved(proposal_hash);
+			Ok(())
+		}
+
+		/// Cancel an open proposal.
+		///
+		/// Can only be called by the proposer.
+		pub fn cancel(origin, proposal_hash: T::Hash) -> Result {
+			let who = ensure_signed(origin)?;
+			Open::<T>::cancel(proposal_hash, &who)?;
+
+			Self::deposit_event(RawEvent::Cancelled(proposal_hash));
+			Ok(())
+		}
+
+		/// Set the number of blocks a proposal stays open using root privileges.
+		pub fn set_proposal_lifetime(lifetime: T::BlockNumber) -> Result {
+			<ProposalLifetime<T>>::put(lifetime);
+			Ok(())
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	// Dispatches a proposal if enough current validators approved it.
+	fn execute_if_approved(proposal_hash: T::Hash) {
+		let details = match Self::details_of(proposal_hash) {
+			Some(details) => details,
+			None => return,
+		};
+
+		let approvals = council::current_approvals::<T>(&details.votes.approvals);
+		let threshold = if details.as_root {
+			<council::Module<T>>::required_approvals()
+		} else {
+			details.threshold
+		};
+		if approvals < threshold {
+			return;
+		}
+
+		if let Some(proposal) = Open::<T>::take(proposal_hash) {
+			let origin: <T as Trait>::Origin = if details.as_root {
+				system::RawOrigin::Root.into()
+			} else {
+				Origin::Validators(approvals, <session::Module<T>>::validator_count()).into()
+			};
+			let ok = proposal.dispatch(origin).is_ok();
+			Self::deposit_event(RawEvent::Executed(proposal_hash, ok));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use support::{assert_ok, assert_noop};
+	use crate::mock::{new_test_ext, signed, events};
+	use crate::{Event, Call, ValidatorSetCall, Council, Multisig, ValidatorSet, RateLimit};
+	use crate::ratelimit::Call as RateLimitCall;
+
+	fn set_delay(delay: u64) -> Box<Call> {
+		Box::new(Call::ValidatorSet(ValidatorSetCall::set_emergency_delay(delay)))
+	}
+
+	fn set_rate_limit(max_transactions: u32) -> Box<Call> {
+		Box::new(Call::RateLimit(RateLimitCall::set_rate_limit(max_transactions, 1)))
+	}
+
+	fn hash_of(call: &Box<Call>) -> crate::Hash {
+		<crate::Runtime as system::Trait>::Hashing::hash_of(call)
+	}
+
+	#[test]
+	fn root_proposals_need_council_mode() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_noop!(Multisig::propose(signed(1), 3, true, set_delay(7)), "Root proposals need council mode.");
+		});
+	}
+
+	#[test]
+	fn root_proposals_use_council_threshold() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_ok!(Council::enable_council(0));
+			let call = set_delay(7);
+			let proposal_hash = hash_of(&call);
+
+			// A threshold of one would execute right away.
+			assert_ok!(Multisig::propose(signed(1), 1, true, call));
+			assert_ok!(Multisig::approve(signed(2), proposal_hash));
+			assert_eq!(ValidatorSet::emergency_delay(), 0);
+
+			assert_ok!(Multisig::approve(signed(3), proposal_hash));
+			assert_eq!(ValidatorSet::emergency_delay(), 7);
+		});
+	}
+
+	#[test]
+	fn validators_origin_is_not_root() {
+		with_externalities(&mut new_test_ext(3), || {
+			let call = set_delay(7);
+			let proposal_hash = hash_of(&call);
+
+			assert_ok!(Multisig::propose(signed(1), 1, false, call));
+			assert_eq!(ValidatorSet::emergency_delay(), 0);
+			assert!(events().contains(&Event::multisig(RawEvent::Executed(proposal_hash, false))));
+		});
+	}
+
+	#[test]
+	fn majority_of_validators_can_change_the_rate_limit() {
+		with_externalities(&mut new_test_ext(3), || {
+			let call = set_rate_limit(5);
+			let proposal_hash = hash_of(&call);
+
+			assert_ok!(Multisig::propose(signed(1), 2, false, call));
+			assert_eq!(RateLimit::max_transactions(), 10);
+
+			assert_ok!(Multisig::approve(signed(2), proposal_hash));
+			assert_eq!(RateLimit::max_transactions(), 5);
+			assert!(events().contains(&Event::multisig(RawEvent::Executed(proposal_hash, true))));
+		});
+	}
+
+	#[test]
+	fn minority_of_validators_cannot_change_the_rate_limit() {
+		with_externalities(&mut new_test_ext(3), || {
+			let call = set_rate_limit(5);
+			let proposal_hash = hash_of(&call);
+
+			assert_ok!(Multisig::propose(signed(1), 1, false, call));
+			assert_eq!(RateLimit::max_transactions(), 10);
+			assert!(events().contains(&Event::multisig(RawEvent::Executed(proposal_hash, false))));
+		});
+	}
+
+	#[test]
+	fn validators_origin_needs_a_majority() {
+		assert_ok!(ensure_majority(Some(Origin::Validators(2, 3))));
+		assert_ok!(ensure_majority(Some(Origin::Validators(3, 4))));
+		assert!(ensure_majority(Some(Origin::Validators(2, 4))).is_err());
+		assert!(ensure_majority(None).is_err());
+	}
+
+	#[test]
+	fn rate_limit_cannot_be_changed_by_a_signed_origin() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert!((*set_rate_limit(5)).dispatch(signed(1)).is_err());
+			assert_ok!((*set_rate_limit(5)).dispatch(system::RawOrigin::Root.into()));
+			assert_eq!(RateLimit::max_transactions(), 5);
+		});
+	}
+}
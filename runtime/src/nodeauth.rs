@@ -0,0 +1,218 @@
+use support::{decl_module, decl_storage, decl_event, StorageValue, StorageMap, ensure, dispatch::Result};
+use rstd::prelude::*;
+use system::{ensure_signed};
+use crate::{validatorset, council};
+
+/// Base58 encoded libp2p `PeerId`, e.g. `QmRpheLN4JWdAnY7HGJfWFNbfkQCb6tFf4vvA6hgjMZKrR`.
+pub type PeerId = Vec<u8>;
+
+pub trait Trait: council::Trait {
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+}
+
+decl_storage! {
+	trait Store for Module<T: Trait> as NodeAuth {
+		/// Whether nodes only accept connections from authorized peers.
+		Enabled get(enabled) config(): bool;
+		/// Peers that are allowed to connect to the network.
+		AuthorizedPeers get(authorized_peers) config(): Vec<PeerId>;
+
+		AddPeerVotes get(add_peer_votes): map PeerId => Vec<T::AccountId>;
+		RemovePeerVotes get(remove_peer_votes): map PeerId => Vec<T::AccountId>;
+	}
+	extra_genesis_skip_phantom_data_field;
+}
+
+decl_event!(
+  pub enum Event<T> where AccountId = <T as system::Trait>::AccountId {
+	  // Vote to authorize a peer. First argument is the AccountId of voter.
+	  PeerAdditionVoted(AccountId, PeerId),
+
+	  // Vote to deauthorize a peer. First argument is the AccountId of voter.
+	  PeerRemovalVoted(AccountId, PeerId),
+
+	  // Peer authorized.
+	  PeerAdded(PeerId),
+
+	  // Peer deauthorized.
+	  PeerRemoved(PeerId),
+
+	  // Peer authorization turned on or off.
+	  PeerAuthorizationSet(bool),
+  }
+);
+
+decl_module! {
+	pub struct Module<T: Trait> for enum Call where origin: T::Origin {
+		fn deposit_event<T>() = default;
+
+		/// Vote to authorize a peer.
+		///
+		/// Can only be called by an existing validator.
+		/// The peer is added once the council threshold of validators voted for it.
+		pub fn vote_add_peer(origin, peer_id: PeerId) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+			ensure!(!Self::authorized_peers().contains(&peer_id), "Peer is already authorized.");
+
+			let mut votes = Self::add_peer_votes(&peer_id);
+			council::add_vote(&mut votes, who.clone())?;
+			<AddPeerVotes<T>>::insert(&peer_id, &votes);
+
+			Self::deposit_event(RawEvent::PeerAdditionVoted(who, peer_id.clone()));
+
+			if <council::Module<T>>::is_approved_by(&votes) {
+				Self::add_authorized_peer(peer_id);
+			}
+			Ok(())
+		}
+
+		/// Vote to deauthorize a peer.
+		///
+		/// Can only be called by an existing validator.
+		/// The peer is removed once the council threshold of validators voted for it.
+		pub fn vote_remove_peer(origin, peer_id: PeerId) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+			ensure!(Self::authorized_peers().contains(&peer_id), "Peer is not authorized.");
+
+			let mut votes = Self::remove_peer_votes(&peer_id);
+			council::add_vote(&mut votes, who.clone())?;
+			<RemovePeerVotes<T>>::insert(&peer_id, &votes);
+
+			Self::deposit_event(RawEvent::PeerRemovalVoted(who, peer_id.clone()));
+
+			if <council::Module<T>>::is_approved_by(&votes) {
+				Self::remove_authorized_peer(peer_id);
+			}
+			Ok(())
+		}
+
+		/// Authorize a peer using root privileges.
+		pub fn add_peer(peer_id: PeerId) -> Result {
+			ensure!(!Self::authorized_peers().contains(&peer_id), "Peer is already authorized.");
+
+			Self::add_authorized_peer(peer_id);
+			Ok(())
+		}
+
+		/// Deauthorize a peer using root privileges.
+		pub fn remove_peer(peer_id: PeerId) -> Result {
+			ensure!(Self::authorized_peers().contains(&peer_id), "Peer is not authorized.");
+
+			Self::remove_authorized_peer(peer_id);
+			Ok(())
+		}
+
+		/// Turn peer authorization on or off using root privileges.
+		pub fn set_enabled(enabled: bool) -> Result {
+			<Enabled<T>>::put(enabled);
+
+			Self::deposit_event(RawEvent::PeerAuthorizationSet(enabled));
+			Ok(())
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	/// Peers nodes should accept, or `None` if any peer is accepted.
+	pub fn peer_allowlist() -> Option<Vec<PeerId>> {
+		if Self::enabled() {
+			Some(Self::authorized_peers())
+		} else {
+			None
+		}
+	}
+
+	fn add_authorized_peer(peer_id: PeerId) {
+		<AuthorizedPeers<T>>::mutate(|peers| peers.push(peer_id.clone()));
+		<AddPeerVotes<T>>::remove(&peer_id);
+
+		Self::deposit_event(RawEvent::PeerAdded(peer_id));
+	}
+
+	fn remove_authorized_peer(peer_id: PeerId) {
+		<AuthorizedPeers<T>>::mutate(|peers| peers.retain(|p| p != &peer_id));
+		<RemovePeerVotes<T>>::remove(&peer_id);
+
+		Self::deposit_event(RawEvent::PeerRemoved(peer_id));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use support::{assert_ok, assert_noop};
+	use runtime_primitives::traits::Dispatchable;
+	use crate::mock::{new_test_ext, signed, events, ROOT};
+	use crate::{Call, Event, NodeAuth};
+	use super::Call as NodeAuthCall;
+
+	fn peer(n: u8) -> PeerId {
+		vec![b'Q', b'm', n]
+	}
+
+	#[test]
+	fn peer_is_added_at_threshold() {
+		with_externalities(&mut new_test_ext(3), || {
+			assert_noop!(NodeAuth::vote_add_peer(signed(ROOT), peer(1)), "Access Denied!");
+
+			assert_ok!(NodeAuth::vote_add_peer(signed(1), peer(1)));
+			assert_noop!(NodeAuth::vote_add_peer(signed(1), peer(1)), "You have already approved this proposal.");
+			assert_ok!(NodeAuth::vote_add_peer(signed(2), peer(1)));
+			assert!(NodeAuth::authorized_peers().is_empty());
+
+			// Without a council threshold all validators have to agree.
+			assert_ok!(NodeAuth::vote_add_peer(signed(3), peer(1)));
+			assert_eq!(NodeAuth::authorized_peers(), vec![peer(1)]);
+			assert!(NodeAuth::add_peer_votes(peer(1)).is_empty());
+			assert!(events().contains(&Event::nodeauth(RawEvent::PeerAdded(peer(1)))));
+			assert_noop!(NodeAuth::vote_add_peer(signed(1), peer(1)), "Peer is already authorized.");
+		});
+	}
+
+	#[test]
+	fn peer_is_removed_at_threshold() {
+		with_externalities(&mut new_test_ext(2), || {
+			assert_ok!(NodeAuth::add_peer(peer(1)));
+			assert_noop!(NodeAuth::vote_remove_peer(signed(1), peer(2)), "Peer is not authorized.");
+
+			assert_ok!(NodeAuth::vote_remove_peer(signed(1), peer(1)));
+			assert_eq!(NodeAuth::authorized_peers(), vec![peer(1)]);
+			assert_ok!(NodeAuth::vote_remove_peer(signed(2), peer(1)));
+			assert!(NodeAuth::authorized_peers().is_empty());
+			assert!(events().contains(&Event::nodeauth(RawEvent::PeerRemoved(peer(1)))));
+		});
+	}
+
+	#[test]
+	fn root_calls_change_the_peers() {
+		with_externalities(&mut new_test_ext(2), || {
+			assert_ok!(NodeAuth::add_peer(peer(1)));
+			assert_noop!(NodeAuth::add_peer(peer(1)), "Peer is already authorized.");
+			assert_ok!(NodeAuth::remove_peer(peer(1)));
+			assert_noop!(NodeAuth::remove_peer(peer(1)), "Peer is not authorized.");
+
+			for call in vec![
+				NodeAuthCall::add_peer(peer(2)),
+				NodeAuthCall::remove_peer(peer(2)),
+				NodeAuthCall::set_enabled(true),
+			] {
+				assert!(Call::NodeAuth(call).dispatch(signed(1)).is_err());
+			}
+		});
+	}
+
+	#[test]
+	fn allowlist_is_only_enforced_when_enabled() {
+		with_externalities(&mut new_test_ext(2), || {
+			assert_ok!(NodeAuth::add_peer(peer(1)));
+			assert_eq!(NodeAuth::peer_allowlist(), None);
+
+			assert_ok!(NodeAuth::set_enabled(true));
+			assert_eq!(NodeAuth::peer_allowlist(), Some(vec![peer(1)]));
+			assert!(events().contains(&Event::nodeauth(RawEvent::PeerAuthorizationSet(true))));
+		});
+	}
+}
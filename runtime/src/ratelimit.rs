@@ -0,0 +1,358 @@
+use support::{decl_module, decl_storage, decl_event, StorageValue, StorageMap, ensure, traits::MakePayment, dispatch::Result};
+use rstd::{prelude::*, marker::PhantomData, result};
+use runtime_primitives::traits::{As, Zero};
+use runtime_primitives::transaction_validity::TransactionValidity;
+use crate::{validatorset, multisig};
+
+pub trait Trait: validatorset::Trait + sudo::Trait {
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+
+	/// Check of the origin of the calls changing the rate limit.
+	type Validators: multisig::EnsureValidators<Self::Origin>;
+}
+
+decl_storage! {
+	trait Store for Module<T: Trait> as RateLimit {
+		/// Whether transactions are rate limited per account.
+		Enabled get(enabled) config(): bool;
+		/// Whether transactions are free. Implies rate limiting.
+		Feeless get(feeless) config(): bool;
+		/// Number of transactions an account can send per window.
+		MaxTransactions get(max_transactions) config(): u32;
+		/// Length of a rate limiting window in blocks.
+		WindowLength get(window_length) config(): T::BlockNumber;
+
+		/// Transactions sent by an account in the current window.
+		Usage get(usage): map T::AccountId => u32;
+		/// Accounts with a non-zero `Usage`, so that it can be reset.
+		ActiveAccounts get(active_accounts): Vec<T::AccountId>;
+	}
+	extra_genesis_skip_phantom_data_field;
+}
+
+decl_event!(
+  pub enum Event<T> where BlockNumber = <T as system::Trait>::BlockNumber {
+	  // Rate limiting turned on or off.
+	  RateLimitingSet(bool),
+
+	  // Fee-less mode turned on or off.
+	  FeelessModeSet(bool),
+
+	  // Rate limit changed to the given number of transactions per window of blocks.
+	  RateLimitSet(u32, BlockNumber),
+  }
+);
+
+decl_module! {
+	pub struct Module<T: Trait> for enum Call where origin: T::Origin {
+		fn deposit_event<T>() = default;
+
+		fn on_finalize(n: T::BlockNumber) {
+			if Self::is_window_end(n) {
+				Self::reset_usage();
+			}
+		}
+
+		/// Turn rate limiting on or off using root privileges or a validator majority.
+		pub fn set_enabled(origin, enabled: bool) -> Result {
+			T::Validators::ensure_root_or_majority(origin)?;
+			if enabled {
+				Self::ensure_valid_limit(Self::max_transactions(), Self::window_length())?;
+			}
+			<Enabled<T>>::put(enabled);
+
+			Self::deposit_event(RawEvent::RateLimitingSet(enabled));
+			Ok(())
+		}
+
+		/// Turn fee-less mode on or off using root privileges or a validator majority.
+		pub fn set_feeless(origin, feeless: bool) -> Result {
+			T::Validators::ensure_root_or_majority(origin)?;
+			if feeless {
+				Self::ensure_valid_limit(Self::max_transactions(), Self::window_length())?;
+			}
+			<Feeless<T>>::put(feeless);
+
+			Self::deposit_event(RawEvent::FeelessModeSet(feeless));
+			Ok(())
+		}
+
+		/// Set the number of transactions per window of blocks using root privileges
+		/// or a validator majority.
+		///
+		/// A limit of zero transactions is only accepted while rate limiting is off.
+		pub fn set_rate_limit(origin, max_transactions: u32, window_length: T::BlockNumber) -> Result {
+			T::Validators::ensure_root_or_majority(origin)?;
+			ensure!(!window_length.is_zero(), "Window length cannot be zero.");
+			if Self::enabled() || Self::feeless() {
+				Self::ensure_valid_limit(max_transactions, window_length)?;
+			}
+
+			<MaxTransactions<T>>::put(max_transactions);
+			<WindowLength<T>>::put(window_length);
+
+			Self::deposit_event(RawEvent::RateLimitSet(max_transactions, window_length));
+			Ok(())
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	/// Whether transactions of the account are rate limited.
+	///
+	/// Validators and the sudo key are exempt so that governance keeps working.
+	pub fn is_limited(who: &T::AccountId) -> bool {
+		(Self::enabled() || Self::feeless())
+			&& !<validatorset::Module<T>>::is_validator(who)
+			&& <sudo::Module<T>>::key() != *who
+	}
+
+	/// Number of transactions the account can still send in the current window.
+	pub fn remaining(who: &T::AccountId) -> u32 {
+		Self::max_transactions().saturating_sub(Self::usage(who))
+	}
+
+	/// Adjusts the pool validity of a transaction sent by `who` to its rate limit.
+	///
+	/// Accounts with more of their quota left get a higher priority, and the
+	/// transaction is only kept until the end of the window in which it was
+	/// validated, after which it is checked against the new quota.
+	///
+	/// The pool validates each transaction on its own against the state of the best
+	/// block and discards the changes made while doing so. The usage seen here thus
+	/// only includes transactions already in blocks, not the other transactions of
+	/// the account waiting in the pool. An account can get more than `max_transactions`
+	/// into the pool; the extra ones fail when applied in a block and are dropped then.
+	pub fn adjust_validity(who: &T::AccountId, validity: TransactionValidity) -> TransactionValidity {
+		if !Self::is_limited(who) {
+			return validity;
+		}
+
+		match validity {
+			TransactionValidity::Valid { requires, provides, longevity, .. } => {
+				let now = <system::Module<T>>::block_number();
+				let window_left = Self::window_end(now) - now + T::BlockNumber::sa(1);
+				TransactionValidity::Valid {
+					priority: Self::remaining(who) as u64,
+					requires,
+					provides,
+					longevity: rstd::cmp::min(longevity, window_left.as_()),
+				}
+			},
+			other => other,
+		}
+	}
+
+	// Rate limiting with a zero limit would lock out every account but the validators and the sudo key.
+	fn ensure_valid_limit(max_transactions: u32, window_length: T::BlockNumber) -> Result {
+		ensure!(max_transactions > 0, "Rate limit must allow at least one transaction.");
+		ensure!(!window_length.is_zero(), "Window length cannot be zero.");
+		Ok(())
+	}
+
+	// Last block of the window containing block `n`.
+	fn window_end(n: T::BlockNumber) -> T::BlockNumber {
+		let length = Self::window_length();
+		if length.is_zero() {
+			return n;
+		}
+		n - n % length + length - T::BlockNumber::sa(1)
+	}
+
+	fn is_window_end(n: T::BlockNumber) -> bool {
+		Self::window_end(n) == n
+	}
+
+	fn reset_usage() {
+		for who in <ActiveAccounts<T>>::take() {
+			<Usage<T>>::remove(who);
+		}
+	}
+
+	// Counts a transaction of the account, failing if it is over the limit.
+	fn note_transaction(who: &T::AccountId) -> result::Result<(), &'static str> {
+		let used = Self::usage(who);
+		if used >= Self::max_transactions() {
+			return Err("rate limit exceeded");
+		}
+
+		if used == 0 {
+			<ActiveAccounts<T>>::mutate(|accounts| accounts.push(who.clone()));
+		}
+		<Usage<T>>::insert(who, used + 1);
+		Ok(())
+	}
+}
+
+/// Transaction payment of the executive.
+///
+/// Counts transactions of rate limited accounts and charges fees through `P`
+/// unless fee-less mode is on. The executive makes the payment both when
+/// validating a transaction for the pool and when applying it, but only the
+/// latter is enforced across transactions, see `Module::adjust_validity`.
+pub struct Payment<T, P>(PhantomData<(T, P)>);
+
+impl<T: Trait, P: MakePayment<T::AccountId>> MakePayment<T::AccountId> for Payment<T, P> {
+	fn make_payment(who: &T::AccountId, encoded_len: usize) -> result::Result<(), &'static str> {
+		if <Module<T>>::is_limited(who) {
+			<Module<T>>::note_transaction(who)?;
+		}
+
+		if <Module<T>>::feeless() {
+			Ok(())
+		} else {
+			P::make_payment(who, encoded_len)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use support::assert_ok;
+	use runtime_primitives::traits::OnFinalize;
+	use crate::mock::{new_test_ext, account, ROOT};
+	use crate::{Runtime, Origin, AccountId, Balances, System, RateLimit};
+
+	type Payment = super::Payment<Runtime, Balances>;
+
+	// Payment that always fails, to tell whether fees are charged.
+	struct Fees;
+
+	impl MakePayment<AccountId> for Fees {
+		fn make_payment(_who: &AccountId, _encoded_len: usize) -> result::Result<(), &'static str> {
+			Err("fees charged")
+		}
+	}
+
+	fn valid(longevity: u64) -> TransactionValidity {
+		TransactionValidity::Valid { priority: 0, requires: vec![], provides: vec![vec![1]], longevity }
+	}
+
+	fn root() -> Origin {
+		system::RawOrigin::Root.into()
+	}
+
+	fn limit(max_transactions: u32, window_length: u64) {
+		assert_ok!(RateLimit::set_rate_limit(root(), max_transactions, window_length));
+		assert_ok!(RateLimit::set_enabled(root(), true));
+	}
+
+	#[test]
+	fn transactions_over_the_limit_are_rejected() {
+		with_externalities(&mut new_test_ext(3), || {
+			limit(2, 5);
+			let user = account(50);
+
+			assert_ok!(Payment::make_payment(&user, 0));
+			assert_ok!(Payment::make_payment(&user, 0));
+			assert_eq!(Payment::make_payment(&user, 0), Err("rate limit exceeded"));
+			assert_eq!(RateLimit::usage(&user), 2);
+			assert_eq!(RateLimit::active_accounts(), vec![user]);
+		});
+	}
+
+	#[test]
+	fn usage_is_reset_at_the_end_of_the_window() {
+		with_externalities(&mut new_test_ext(3), || {
+			limit(1, 5);
+			let user = account(50);
+			assert_ok!(Payment::make_payment(&user, 0));
+
+			<RateLimit as OnFinalize<u64>>::on_finalize(3);
+			assert_eq!(Payment::make_payment(&user, 0), Err("rate limit exceeded"));
+
+			<RateLimit as OnFinalize<u64>>::on_finalize(4);
+			assert_eq!(RateLimit::usage(&user), 0);
+			assert!(RateLimit::active_accounts().is_empty());
+			assert_ok!(Payment::make_payment(&user, 0));
+		});
+	}
+
+	#[test]
+	fn validators_and_the_sudo_key_are_exempt() {
+		with_externalities(&mut new_test_ext(3), || {
+			limit(1, 5);
+
+			for who in &[account(1), account(ROOT)] {
+				assert!(!RateLimit::is_limited(who));
+				for _ in 0..3 {
+					assert_ok!(Payment::make_payment(who, 0));
+				}
+				assert_eq!(RateLimit::usage(who), 0);
+			}
+			assert!(RateLimit::is_limited(&account(50)));
+		});
+	}
+
+	#[test]
+	fn priority_is_the_remaining_quota() {
+		with_externalities(&mut new_test_ext(3), || {
+			limit(3, 5);
+			let user = account(50);
+			assert_ok!(Payment::make_payment(&user, 0));
+
+			match RateLimit::adjust_validity(&user, valid(64)) {
+				TransactionValidity::Valid { priority, provides, .. } => {
+					assert_eq!(priority, 2);
+					assert_eq!(provides, vec![vec![1]]);
+				},
+				_ => panic!("transaction rejected"),
+			}
+
+			// Exempt accounts keep the validity of the executive.
+			assert_eq!(RateLimit::adjust_validity(&account(1), valid(64)), valid(64));
+		});
+	}
+
+	#[test]
+	fn longevity_ends_with_the_window() {
+		with_externalities(&mut new_test_ext(3), || {
+			limit(3, 5);
+			let user = account(50);
+			let longevity = |validity| match validity {
+				TransactionValidity::Valid { longevity, .. } => longevity,
+				_ => panic!("transaction rejected"),
+			};
+
+			// Blocks 5 to 9 form a window.
+			System::set_block_number(5);
+			assert_eq!(longevity(RateLimit::adjust_validity(&user, valid(64))), 5);
+			System::set_block_number(9);
+			assert_eq!(longevity(RateLimit::adjust_validity(&user, valid(64))), 1);
+			assert_eq!(longevity(RateLimit::adjust_validity(&user, valid(0))), 0);
+		});
+	}
+
+	#[test]
+	fn transactions_are_rejected_once_the_quota_is_used() {
+		with_externalities(&mut new_test_ext(3), || {
+			limit(1, 5);
+			let user = account(50);
+			assert_ok!(Payment::make_payment(&user, 0));
+
+			// The pool makes the payment before adjusting the validity, so the
+			// transaction is rejected there.
+			assert_eq!(Payment::make_payment(&user, 0), Err("rate limit exceeded"));
+			assert_eq!(RateLimit::remaining(&user), 0);
+			match RateLimit::adjust_validity(&user, valid(64)) {
+				TransactionValidity::Valid { priority, .. } => assert_eq!(priority, 0),
+				_ => panic!("transaction rejected"),
+			}
+		});
+	}
+
+	#[test]
+	fn feeless_mode_skips_fees() {
+		with_externalities(&mut new_test_ext(3), || {
+			let user = account(50);
+			assert_eq!(super::Payment::<Runtime, Fees>::make_payment(&user, 0), Err("fees charged"));
+			assert_eq!(RateLimit::usage(&user), 0);
+
+			assert_ok!(RateLimit::set_feeless(root(), true));
+			assert_ok!(super::Payment::<Runtime, Fees>::make_payment(&user, 0));
+			assert_eq!(RateLimit::usage(&user), 1);
+		});
+	}
+}
@@ -0,0 +1,388 @@
+use support::{decl_module, decl_storage, decl_event, StorageValue, ensure, dispatch::Result};
+use rstd::prelude::*;
+use system::{ensure_signed};
+use runtime_primitives::traits::{Hash, Dispatchable};
+use parity_codec::{Encode, Decode};
+use version::RuntimeVersion;
+use crate::{validatorset, council};
+
+/// Provides the version of the runtime that is currently executing.
+pub trait CurrentVersion {
+	fn current_version() -> RuntimeVersion;
+}
+
+/// A runtime upgrade going through validator approval.
+#[derive(Encode, Decode, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct UpgradeProposal<AccountId, Hash, BlockNumber> {
+	/// Validator that proposed the upgrade.
+	pub proposer: AccountId,
+	/// Hash of the wasm blob.
+	pub code_hash: Hash,
+	/// `spec_name` of the new runtime. Must match the current one.
+	pub spec_name: Vec<u8>,
+	/// `spec_version` of the new runtime. Must be higher than the current one.
+	pub spec_version: u32,
+	/// Validators that approved the upgrade.
+	pub approvals: Vec<AccountId>,
+	/// Validators that voted to cancel the upgrade.
+	pub cancellations: Vec<AccountId>,
+	/// Block at which the code gets set, once it has been uploaded.
+	pub scheduled_at: Option<BlockNumber>,
+}
+
+/// Name of the wasm custom section declaring the version of a runtime.
+pub const VERSION_SECTION: &[u8] = b"poa_version";
+
+pub trait Trait: council::Trait {
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+
+	/// Version of the running runtime.
+	type Version: CurrentVersion;
+}
+
+decl_storage! {
+	trait Store for Module<T: Trait> as Upgrade {
+		/// Number of blocks between uploading the approved code and setting it.
+		UpgradeDelay get(upgrade_delay) config(): T::BlockNumber;
+
+		/// The upgrade currently in progress.
+		PendingUpgrade get(pending_upgrade): Option<UpgradeProposal<T::AccountId, T::Hash, T::BlockNumber>>;
+		/// The uploaded wasm blob of the pending upgrade.
+		PendingCode get(pending_code): Option<Vec<u8>>;
+	}
+	extra_genesis_skip_phantom_data_field;
+}
+
+decl_event!(
+  pub enum Event<T> where AccountId = <T as system::Trait>::AccountId, Hash = <T as system::Trait>::Hash,
+  BlockNumber = <T as system::Trait>::BlockNumber {
+	  // Runtime upgrade proposed. First argument is the AccountId of proposer, last one the new spec_version.
+	  UpgradeProposed(AccountId, Hash, u32),
+
+	  // Runtime upgrade approved by a validator.
+	  UpgradeApproved(AccountId, Hash),
+
+	  // Code of an approved upgrade uploaded. It is set at the given block.
+	  UpgradeScheduled(Hash, BlockNumber),
+
+	  // Runtime upgrade applied.
+	  UpgradeApplied(Hash, u32),
+
+	  // Setting the code of a scheduled runtime upgrade failed. The upgrade is dropped.
+	  UpgradeFailed(Hash),
+
+	  // Runtime upgrade cancelled.
+	  UpgradeCancelled(Hash),
+
+	  // A validator voted to cancel the runtime upgrade.
+	  CancellationVoted(AccountId, Hash),
+  }
+);
+
+decl_module! {
+	pub struct Module<T: Trait> for enum Call where origin: T::Origin {
+		fn deposit_event<T>() = default;
+
+		fn on_initialize(n: T::BlockNumber) {
+			Self::apply_scheduled_upgrade(n);
+		}
+
+		/// Propose a runtime upgrade by the hash of its wasm blob.
+		///
+		/// Can only be called by an existing validator and counts as its approval.
+		/// `spec_name` must match the running runtime and `spec_version` must be higher.
+		pub fn propose_upgrade(origin, code_hash: T::Hash, spec_name: Vec<u8>, spec_version: u32) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+			ensure!(Self::pending_upgrade().is_none(), "Another upgrade is in progress.");
+
+			let current = T::Version::current_version();
+			ensure!(&spec_name[..] == current.spec_name.as_bytes(), "Incompatible spec_name.");
+			ensure!(spec_version > current.spec_version, "spec_version must be higher than the current one.");
+
+			<PendingUpgrade<T>>::put(UpgradeProposal {
+				proposer: who.clone(),
+				code_hash,
+				spec_name,
+				spec_version,
+				approvals: vec![who.clone()],
+				cancellations: Vec::new(),
+				scheduled_at: None,
+			});
+
+			Self::deposit_event(RawEvent::UpgradeProposed(who, code_hash, spec_version));
+			Ok(())
+		}
+
+		/// Approve the pending runtime upgrade.
+		///
+		/// Can only be called by an existing validator.
+		pub fn approve_upgrade(origin, code_hash: T::Hash) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+
+			let mut upgrade = Self::pending_upgrade().ok_or("No upgrade is in progress.")?;
+			ensure!(upgrade.code_hash == code_hash, "Code hash does not match the pending upgrade.");
+			ensure!(!upgrade.approvals.contains(&who), "You have already approved this upgrade.");
+
+			upgrade.approvals.push(who.clone());
+			<PendingUpgrade<T>>::put(upgrade);
+
+			Self::deposit_event(RawEvent::UpgradeApproved(who, code_hash));
+			Ok(())
+		}
+
+		/// Upload the wasm blob of an approved upgrade and schedule it.
+		///
+		/// Can only be called by an existing validator. The version declared by the
+		/// blob in its `poa_version` section must match the approved one.
+		pub fn upload_code(origin, code: Vec<u8>) -> Result {
+			let who = ensure_signed(origin)?;
+			ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+
+			let mut upgrade = Self::pending_upgrade().ok_or("No upgrade is in progress.")?;
+			ensure!(upgrade.scheduled_at.is_none(), "Code has already been uploaded.");
+			ensure!(Self::is_approved(&upgrade), "Not enough approvals.");
+			ensure!(T::Hashing::hash(&code) == upgrade.code_hash, "Code does not match the approved hash.");
+
+			let (spec_name, spec_version) = code_version(&code).ok_or("Code does not declare its version.")?;
+			ensure!(spec_name == upgrade.spec_name && spec_version == upgrade.spec_version,
+				"Code version does not match the approved upgrade.");
+
+			let scheduled_at = <system::Module<T>>::block_number() + Self::upgrade_delay();
+			upgrade.scheduled_at = Some(scheduled_at);
+			let code_hash = upgrade.code_hash;
+			<PendingUpgrade<T>>::put(upgrade);
+			<PendingCode<T>>::put(code);
+
+			Self::deposit_event(RawEvent::UpgradeScheduled(code_hash, scheduled_at));
+			Ok(())
+		}
+
+		/// Cancel the pending runtime upgrade, or vote to cancel it.
+		///
+		/// The proposer cancels the upgrade right away. Other validators vote, and the
+		/// upgrade is cancelled once a majority of the current validators voted.
+		pub fn cancel_upgrade(origin) -> Result {
+			let who = ensure_signed(origin)?;
+			let mut upgrade = Self::pending_upgrade().ok_or("No upgrade is in progress.")?;
+
+			if upgrade.proposer != who {
+				ensure!(<validatorset::Module<T>>::is_validator(&who), "Access Denied!");
+				ensure!(!upgrade.cancellations.contains(&who), "You have already voted to cancel this upgrade.");
+				upgrade.cancellations.push(who.clone());
+
+				let votes = council::current_approvals::<T>(&upgrade.cancellations);
+				if votes <= <session::Module<T>>::validator_count() / 2 {
+					let code_hash = upgrade.code_hash;
+					<PendingUpgrade<T>>::put(upgrade);
+					Self::deposit_event(RawEvent::CancellationVoted(who, code_hash));
+					return Ok(());
+				}
+			}
+
+			Self::remove_upgrade(upgrade.code_hash);
+			Ok(())
+		}
+
+		/// Cancel the pending runtime upgrade using root privileges, e.g. through the council.
+		pub fn force_cancel_upgrade() -> Result {
+			let upgrade = Self::pending_upgrade().ok_or("No upgrade is in progress.")?;
+			Self::remove_upgrade(upgrade.code_hash);
+			Ok(())
+		}
+	}
+}
+
+/// `spec_name` and `spec_version` declared by a wasm blob in its `poa_version` custom section.
+///
+/// The section holds the `spec_version` as four little endian bytes, followed by the `spec_name`.
+pub fn code_version(code: &[u8]) -> Option<(Vec<u8>, u32)> {
+	if code.len() < 8 || &code[..4] != b"\0asm" {
+		return None;
+	}
+
+	// Sections follow the magic number and the format version.
+	let mut pos = 8;
+	while pos < code.len() {
+		let id = code[pos];
+		pos += 1;
+		let len = read_varuint(code, &mut pos)? as usize;
+		let end = pos.checked_add(len).filter(|end| *end <= code.len())?;
+
+		if id == 0 {
+			let section = &code[..end];
+			let mut name_pos = pos;
+			let name_len = read_varuint(section, &mut name_pos)? as usize;
+			let name_end = name_pos.checked_add(name_len).filter(|e| *e <= end)?;
+			if &section[name_pos..name_end] == VERSION_SECTION {
+				let payload = &section[name_end..];
+				if payload.len() < 4 {
+					return None;
+				}
+				let spec_version = u32::from(payload[0]) | u32::from(payload[1]) << 8
+					| u32::from(payload[2]) << 16 | u32::from(payload[3]) << 24;
+				return Some((payload[4..].to_vec(), spec_version));
+			}
+		}
+		pos = end;
+	}
+	None
+}
+
+// Reads an unsigned LEB128 number of at most 32 bits.
+fn read_varuint(data: &[u8], pos: &mut usize) -> Option<u32> {
+	let mut result = 0u32;
+	let mut shift = 0;
+	loop {
+		let byte = *data.get(*pos)?;
+		*pos += 1;
+		result |= u32::from(byte & 0x7f) << shift;
+		if byte & 0x80 == 0 {
+			return Some(result);
+		}
+		shift += 7;
+		if shift > 28 {
+			return None;
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	// Same threshold as the council: all validators unless configured otherwise.
+	fn is_approved(upgrade: &UpgradeProposal<T::AccountId, T::Hash, T::BlockNumber>) -> bool {
+		council::current_approvals::<T>(&upgrade.approvals) >= <council::Module<T>>::required_approvals()
+	}
+
+	fn remove_upgrade(code_hash: T::Hash) {
+		<PendingUpgrade<T>>::kill();
+		<PendingCode<T>>::kill();
+
+		Self::deposit_event(RawEvent::UpgradeCancelled(code_hash));
+	}
+
+	fn apply_scheduled_upgrade(n: T::BlockNumber) {
+		let upgrade = match Self::pending_upgrade() {
+			Some(ref upgrade) if upgrade.scheduled_at.map_or(false, |at| n >= at) => upgrade.clone(),
+			_ => return,
+		};
+
+		<PendingUpgrade<T>>::kill();
+		if let Some(code) = <PendingCode<T>>::take() {
+			let applied = consensus::Call::<T>::set_code(code)
+				.dispatch(system::RawOrigin::Root.into())
+				.is_ok();
+			if applied {
+				Self::deposit_event(RawEvent::UpgradeApplied(upgrade.code_hash, upgrade.spec_version));
+			} else {
+				Self::deposit_event(RawEvent::UpgradeFailed(upgrade.code_hash));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use support::{assert_ok, assert_noop};
+	use runtime_primitives::traits::OnInitialize;
+	use crate::mock::{new_test_ext, signed, events};
+	use crate::{Event, Upgrade, SPEC_VERSION};
+
+	// A wasm module with a type section and a `poa_version` section.
+	fn code(spec_name: &[u8], spec_version: u32) -> Vec<u8> {
+		let mut payload = vec![VERSION_SECTION.len() as u8];
+		payload.extend_from_slice(VERSION_SECTION);
+		payload.extend((0..4).map(|i| (spec_version >> (8 * i)) as u8));
+		payload.extend_from_slice(spec_name);
+
+		let mut code = b"\0asm".to_vec();
+		code.extend_from_slice(&[1, 0, 0, 0]);
+		code.extend_from_slice(&[1, 1, 0]);
+		code.push(0);
+		code.push(payload.len() as u8);
+		code.extend(payload);
+		code
+	}
+
+	fn propose(code: &[u8], spec_version: u32) {
+		let code_hash = <crate::Runtime as system::Trait>::Hashing::hash(code);
+		assert_ok!(Upgrade::propose_upgrade(signed(1), code_hash, b"substrate-poa".to_vec(), spec_version));
+		assert_ok!(Upgrade::approve_upgrade(signed(2), code_hash));
+		assert_ok!(Upgrade::approve_upgrade(signed(3), code_hash));
+	}
+
+	#[test]
+	fn reads_version_section() {
+		assert_eq!(code_version(&code(b"substrate-poa", 300)), Some((b"substrate-poa".to_vec(), 300)));
+		assert_eq!(code_version(b"\0asm\x01\0\0\0"), None);
+		assert_eq!(code_version(b"not wasm"), None);
+
+		// Section length past the end of the blob.
+		let mut truncated = code(b"substrate-poa", 300);
+		truncated.pop();
+		assert_eq!(code_version(&truncated), None);
+	}
+
+	#[test]
+	fn upload_checks_declared_version() {
+		with_externalities(&mut new_test_ext(3), || {
+			let wrong = code(b"substrate-poa", SPEC_VERSION + 2);
+			propose(&wrong, SPEC_VERSION + 1);
+			assert_noop!(Upgrade::upload_code(signed(1), wrong), "Code version does not match the approved upgrade.");
+		});
+
+		with_externalities(&mut new_test_ext(3), || {
+			let right = code(b"substrate-poa", SPEC_VERSION + 1);
+			propose(&right, SPEC_VERSION + 1);
+			assert_ok!(Upgrade::upload_code(signed(1), right));
+			assert!(Upgrade::pending_upgrade().unwrap().scheduled_at.is_some());
+		});
+	}
+
+	#[test]
+	fn scheduled_upgrade_sets_the_code() {
+		with_externalities(&mut new_test_ext(3), || {
+			let new_code = code(b"substrate-poa", SPEC_VERSION + 1);
+			let code_hash = <crate::Runtime as system::Trait>::Hashing::hash(&new_code);
+			propose(&new_code, SPEC_VERSION + 1);
+			assert_ok!(Upgrade::upload_code(signed(1), new_code.clone()));
+			let scheduled_at = Upgrade::pending_upgrade().unwrap().scheduled_at.unwrap();
+
+			<Upgrade as OnInitialize<u64>>::on_initialize(scheduled_at - 1);
+			assert!(Upgrade::pending_upgrade().is_some());
+
+			<Upgrade as OnInitialize<u64>>::on_initialize(scheduled_at);
+			assert!(Upgrade::pending_upgrade().is_none());
+			assert!(Upgrade::pending_code().is_none());
+			assert_eq!(runtime_io::storage(primitives::storage::well_known_keys::CODE), Some(new_code));
+			assert!(events().contains(&Event::upgrade(RawEvent::UpgradeApplied(code_hash, SPEC_VERSION + 1))));
+		});
+	}
+
+	#[test]
+	fn majority_of_validators_can_cancel() {
+		with_externalities(&mut new_test_ext(3), || {
+			propose(&code(b"substrate-poa", SPEC_VERSION + 1), SPEC_VERSION + 1);
+
+			assert_ok!(Upgrade::cancel_upgrade(signed(2)));
+			assert_noop!(Upgrade::cancel_upgrade(signed(2)), "You have already voted to cancel this upgrade.");
+			assert!(Upgrade::pending_upgrade().is_some());
+
+			assert_ok!(Upgrade::cancel_upgrade(signed(3)));
+			assert!(Upgrade::pending_upgrade().is_none());
+		});
+	}
+
+	#[test]
+	fn root_can_cancel() {
+		with_externalities(&mut new_test_ext(3), || {
+			propose(&code(b"substrate-poa", SPEC_VERSION + 1), SPEC_VERSION + 1);
+
+			assert_ok!(Upgrade::force_cancel_upgrade());
+			assert!(Upgrade::pending_upgrade().is_none());
+		});
+	}
+}
@@ -1,26 +1,100 @@
-use support::{decl_module, decl_storage, decl_event, StorageMap, ensure, dispatch::Result};
+use support::{decl_module, decl_storage, decl_event, StorageValue, StorageMap, ensure, dispatch::Result};
 use rstd::prelude::*;
 use system::{ensure_signed};
+use runtime_primitives::traits::{As, Zero};
+use parity_codec::{Encode, Decode};
+#[cfg(feature = "std")]
+use serde_derive::{Serialize, Deserialize};
 use session;
 
-pub trait Trait: system::Trait + session::Trait {
+/// Block authorship counters of a single validator.
+#[derive(Encode, Decode, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "std", derive(Debug, Serialize, Deserialize))]
+pub struct ValidatorStats {
+	/// Blocks authored in the current session.
+	pub session_authored: u32,
+	/// Slots missed in the current session.
+	pub session_missed: u32,
+	/// Blocks authored since the validator was added.
+	pub total_authored: u64,
+	/// Slots missed since the validator was added.
+	pub total_missed: u64,
+}
+
+/// A pending proposal to add or remove a validator.
+#[derive(Encode, Decode, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct Proposal<AccountId, BlockNumber> {
+	/// Validators that voted for the proposal, in voting order.
+	pub votes: Vec<AccountId>,
+	/// Block at which the proposal was made, or migrated.
+	pub proposed_at: BlockNumber,
+}
+
+/// Version of the storage layout used by this code.
+///
+/// Bump it together with `spec_version` whenever the layout changes
+/// and add the corresponding step to `Module::migrate`.
+pub const STORAGE_VERSION: u32 = 1;
+
+/// Number of slots before the last block in which equivocations can be reported.
+///
+/// Older reports are refused, so recorded slots can be pruned without accepting them again.
+pub const EQUIVOCATION_WINDOW: u64 = 4096;
+
+/// Maximum number of equivocations of one validator recorded within the window.
+pub const MAX_EQUIVOCATIONS: usize = 64;
+
+pub trait Trait: system::Trait + session::Trait + aura::Trait
+	+ grandpa::Trait<SessionKey = <Self as consensus::Trait>::SessionKey>
+{
 	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
 }
 
 decl_storage! {
 	trait Store for Module<T: Trait> as ValidatorSet {
+		/// Version of the storage layout, see `STORAGE_VERSION`.
+		StorageVersion get(storage_version) build(|_: &GenesisConfig<T>| STORAGE_VERSION): u32;
+
 		Validators get(validators) config(): map T::AccountId => T::SessionKey;
-		AddProposals get(add_proposals): map (T::AccountId, T::SessionKey) => bool;
-		RemovalProposals get(removal_proposals): map (T::AccountId, T::SessionKey) => bool;
-		AddVotes get(add_votes): map (T::AccountId, T::SessionKey) => Vec<T::AccountId>;
-		RemovalVotes get(removal_votes): map (T::AccountId, T::SessionKey) => Vec<T::AccountId>;
+		PendingAdditions get(pending_additions): map (T::AccountId, T::SessionKey) => Option<Proposal<T::AccountId, T::BlockNumber>>;
+		PendingRemovals get(pending_removals): map (T::AccountId, T::SessionKey) => Option<Proposal<T::AccountId, T::BlockNumber>>;
+		/// Keys of `PendingAdditions`, so that the proposals can be enumerated.
+		AdditionKeys get(addition_keys): Vec<(T::AccountId, T::SessionKey)>;
+		/// Keys of `PendingRemovals`, so that the proposals can be enumerated.
+		RemovalKeys get(removal_keys): Vec<(T::AccountId, T::SessionKey)>;
+
+		// Storage version 0 layout. Only read by `migrate_to_v1`.
+		AddProposals: map (T::AccountId, T::SessionKey) => bool;
+		RemovalProposals: map (T::AccountId, T::SessionKey) => bool;
+		AddVotes: map (T::AccountId, T::SessionKey) => Vec<T::AccountId>;
+		RemovalVotes: map (T::AccountId, T::SessionKey) => Vec<T::AccountId>;
+
+		/// Authorship counters of each validator.
+		AuthorshipStats get(authorship_stats): map T::AccountId => ValidatorStats;
+		/// Aura slot of the last processed block.
+		LastSlot get(last_slot): u64;
+		/// Aura authorities at the start of the current block, which author the next one.
+		BlockAuthorities: Vec<T::SessionKey>;
+		/// Slots of the last `EQUIVOCATION_WINDOW` slots in which each validator was
+		/// reported to sign two different blocks.
+		Equivocations get(equivocations): map T::AccountId => Vec<u64>;
+
+		/// Number of blocks between announcing and applying an emergency validator set.
+		EmergencyDelay get(emergency_delay) config(): T::BlockNumber;
+		/// Announced emergency validator set, the block at which it will be applied and
+		/// the finalized block from which its GRANDPA voters resume.
+		PendingEmergencySet get(pending_emergency_set): Option<(T::BlockNumber, T::BlockNumber, Vec<(T::AccountId, T::SessionKey)>)>;
+		/// Finalized block from which the voters of an applied emergency set resume,
+		/// until the forced GRANDPA change has been scheduled.
+		ForcedFinalityBase get(forced_finality_base): Option<T::BlockNumber>;
 	}
 	extra_genesis_skip_phantom_data_field;
 }
 
 decl_event!(
   pub enum Event<T> where AccountId = <T as system::Trait>::AccountId, 
-  SessionKey = <T as consensus::Trait>::SessionKey {
+  SessionKey = <T as consensus::Trait>::SessionKey, BlockNumber = <T as system::Trait>::BlockNumber {
 	  // New validator proposed. First argument is the AccountId of proposer.
 	  ValidatorProposed(AccountId, AccountId, SessionKey),
 
@@ -32,6 +106,21 @@ decl_event!(
 
 	  // Validator removed.
 	  ValidatorRemoved(AccountId, SessionKey),
+
+	  // Emergency validator set announced. First argument is the block at which it will be applied.
+	  EmergencySetAnnounced(BlockNumber, Vec<AccountId>),
+
+	  // Pending emergency validator set cancelled.
+	  EmergencySetCancelled,
+
+	  // Emergency validator set applied.
+	  EmergencySetApplied(Vec<AccountId>),
+
+	  // Pending emergency validator set could not be applied and was dropped.
+	  EmergencySetFailed(Vec<AccountId>),
+
+	  // Storage migrated from the first to the second layout version.
+	  StorageMigrated(u32, u32),
   }
 );
 
@@ -39,6 +128,27 @@ decl_module! {
 	pub struct Module<T: Trait> for enum Call where origin: T::Origin {
 		fn deposit_event<T>() = default;
 
+		fn on_initialize(n: T::BlockNumber) {
+			Self::migrate();
+			// Before the emergency set below can change the authorities.
+			Self::note_authorship();
+
+			if let Some((apply_at, finalized, new_set)) = Self::pending_emergency_set() {
+				if n >= apply_at {
+					let accounts = new_set.iter().map(|x| x.0.clone()).collect();
+					// A set that fails once would fail in every later block as well,
+					// so it is dropped either way.
+					<PendingEmergencySet<T>>::kill();
+					match Self::replace_authorities(new_set, finalized) {
+						Ok(()) => Self::deposit_event(RawEvent::EmergencySetApplied(accounts)),
+						Err(_) => Self::deposit_event(RawEvent::EmergencySetFailed(accounts)),
+					}
+				}
+			}
+
+			Self::sync_finality_authorities();
+		}
+
 		/// Propose a new validator to be added.
 		/// 
 		/// Can only be called by an existing validator.
@@ -47,17 +157,20 @@ decl_module! {
 			ensure!(<Validators<T>>::exists(who.clone()), "Access Denied!");
 			ensure!(!<Validators<T>>::exists(account_id.clone()), "Already a validator.");
 
-			if <AddProposals<T>>::exists((account_id.clone(), session_key.clone())) {
-				let votes = <AddVotes<T>>::get((account_id.clone(), session_key.clone()));
-				let v = votes.into_iter().find(|x| x == &who);
-				ensure!(v == None, "You have already proposed this validator.");
-			} else {
-				<AddProposals<T>>::insert((account_id.clone(), session_key.clone()), true);
-			}
+			let key = (account_id.clone(), session_key.clone());
+			let mut proposal = match Self::pending_additions(&key) {
+				Some(proposal) => {
+					ensure!(!proposal.votes.contains(&who), "You have already proposed this validator.");
+					proposal
+				},
+				None => {
+					<AdditionKeys<T>>::mutate(|keys| keys.push(key.clone()));
+					Proposal { votes: Vec::new(), proposed_at: <system::Module<T>>::block_number() }
+				},
+			};
 
-			<AddVotes<T>>::mutate((account_id.clone(), session_key.clone()), |vote_list| {
-				vote_list.push(who.clone());
-			});
+			proposal.votes.push(who.clone());
+			<PendingAdditions<T>>::insert(key, proposal);
 			
 			Self::deposit_event(RawEvent::ValidatorProposed(who, account_id, session_key));
 			Ok(())
@@ -71,12 +184,11 @@ decl_module! {
 			let _who = ensure_signed(origin)?;
 
 			ensure!(!<Validators<T>>::exists(account_id.clone()), "Already a validator.");
-			ensure!(<AddProposals<T>>::exists((account_id.clone(), session_key.clone())), 
-				"Proposal to add this validator does not exist.");
+			let proposal = Self::pending_additions(&(account_id.clone(), session_key.clone()))
+				.ok_or("Proposal to add this validator does not exist.")?;
 			
-			let votes = <AddVotes<T>>::get((account_id.clone(), session_key.clone()));
 			let current_count = <session::Module<T>>::validator_count();
-			ensure!(votes.len() as u32 == current_count, "Not enough votes.");
+			ensure!(proposal.votes.len() as u32 == current_count, "Not enough votes.");
 			
 			Self::add_new_authority(account_id, session_key)?;
 			Ok(())
@@ -101,17 +213,20 @@ decl_module! {
 			ensure!(<Validators<T>>::exists(who.clone()), "Access Denied!");
 			ensure!(<Validators<T>>::exists(account_id.clone()), "Not a validator.");
 
-			if <RemovalProposals<T>>::exists((account_id.clone(), session_key.clone())) {
-				let votes = <RemovalVotes<T>>::get((account_id.clone(), session_key.clone()));
-				let v = votes.into_iter().find(|x| x == &who);
-				ensure!(v == None, "You have already proposed removal of this validator.");
-			} else {
-				<RemovalProposals<T>>::insert((account_id.clone(), session_key.clone()), true);
-			}
+			let key = (account_id.clone(), session_key.clone());
+			let mut proposal = match Self::pending_removals(&key) {
+				Some(proposal) => {
+					ensure!(!proposal.votes.contains(&who), "You have already proposed removal of this validator.");
+					proposal
+				},
+				None => {
+					<RemovalKeys<T>>::mutate(|keys| keys.push(key.clone()));
+					Proposal { votes: Vec::new(), proposed_at: <system::Module<T>>::block_number() }
+				},
+			};
 
-			<RemovalVotes<T>>::mutate((account_id.clone(), session_key.clone()), |vote_list| {
-				vote_list.push(who.clone());
-			});
+			proposal.votes.push(who.clone());
+			<PendingRemovals<T>>::insert(key, proposal);
 			
 			Self::deposit_event(RawEvent::ValidatorRemovalProposed(who, account_id, session_key));
 			Ok(())
@@ -123,16 +238,15 @@ decl_module! {
 			let _who = ensure_signed(origin)?;
 
 			ensure!(<Validators<T>>::exists(account_id.clone()), "Not a validator.");
-			ensure!(<RemovalProposals<T>>::exists((account_id.clone(), session_key.clone())), 
-				"Proposal to remove this validator does not exist.");
+			let proposal = Self::pending_removals(&(account_id.clone(), session_key.clone()))
+				.ok_or("Proposal to remove this validator does not exist.")?;
 			
-			let votes = <RemovalVotes<T>>::get((account_id.clone(), session_key.clone()));
 			let current_count = <session::Module<T>>::validator_count();
 
 			// To avoid iterating over two vecs to check if every other validator has voted,
 			// we are simply comparing the length.
 			// This is still safe enough because you cannot vote twice.
-			ensure!(votes.len() as u32 == current_count - 1, "Not enough votes.");
+			ensure!(proposal.votes.len() as u32 == current_count - 1, "Not enough votes.");
 			
 			Self::remove_authority(account_id, session_key)?;
 			Ok(())
@@ -146,6 +260,49 @@ decl_module! {
 
 			Ok(())
 		}
+
+		/// Replace the whole validator set using root/sudo privileges.
+		///
+		/// Meant as an escape hatch when the keys of the current validators are compromised.
+		/// If `EmergencyDelay` is non-zero, the new set is only announced and gets applied
+		/// after that many blocks, unless cancelled with `cancel_emergency_set`.
+		///
+		/// The old GRANDPA voters may not be able to finalize anything anymore, so the new
+		/// voters are forced in and resume from block `finalized`, which must already be
+		/// finalized, e.g. the block returned by `chain_getFinalizedHead`.
+		pub fn emergency_set_validators(new_set: Vec<(T::AccountId, T::SessionKey)>, finalized: T::BlockNumber) -> Result {
+			Self::ensure_valid_set(&new_set)?;
+			ensure!(Self::pending_emergency_set().is_none(), "An emergency validator set is already pending.");
+			ensure!(finalized <= <system::Module<T>>::block_number(), "Finalized block is in the future.");
+
+			let accounts: Vec<T::AccountId> = new_set.iter().map(|x| x.0.clone()).collect();
+			let delay = Self::emergency_delay();
+			if delay.is_zero() {
+				Self::replace_authorities(new_set, finalized)?;
+				Self::deposit_event(RawEvent::EmergencySetApplied(accounts));
+			} else {
+				let apply_at = <system::Module<T>>::block_number() + delay;
+				<PendingEmergencySet<T>>::put((apply_at, finalized, new_set));
+				Self::deposit_event(RawEvent::EmergencySetAnnounced(apply_at, accounts));
+			}
+
+			Ok(())
+		}
+
+		/// Cancel a pending emergency validator set using root/sudo privileges.
+		pub fn cancel_emergency_set() -> Result {
+			ensure!(Self::pending_emergency_set().is_some(), "No emergency validator set is pending.");
+			<PendingEmergencySet<T>>::kill();
+
+			Self::deposit_event(RawEvent::EmergencySetCancelled);
+			Ok(())
+		}
+
+		/// Set the delay of emergency validator set changes using root/sudo privileges.
+		pub fn set_emergency_delay(delay: T::BlockNumber) -> Result {
+			<EmergencyDelay<T>>::put(delay);
+			Ok(())
+		}
 	}
 }
 
@@ -180,14 +337,506 @@ impl<T: Trait> Module<T> {
 		<session::Module<T>>::rotate_session(true, false);
 		<Validators<T>>::remove(account_id.clone());
 
+		<AuthorshipStats<T>>::remove(account_id.clone());
+
 		// Removing the proposals and votes so that it can be added again.
 		// Should they be preserved or archived in any way?
-		<AddProposals<T>>::remove((account_id.clone(), session_key.clone()));
-		<RemovalProposals<T>>::remove((account_id.clone(), session_key.clone()));
-		<AddVotes<T>>::remove((account_id.clone(), session_key.clone()));
-		<RemovalVotes<T>>::remove((account_id.clone(), session_key.clone()));
+		Self::clear_proposals(account_id.clone(), session_key.clone());
 		
 		Self::deposit_event(RawEvent::ValidatorRemoved(account_id, session_key));
 		Ok(())
 	}
-}
\ No newline at end of file
+
+	// Keeps the GRANDPA voters equal to the Aura authorities, which follow the
+	// validator set through session rotations.
+	//
+	// Runs in `on_initialize`: the GRANDPA module only signals a change to the node in
+	// `on_finalize` of the block it was scheduled in, and runs before this module.
+	fn sync_finality_authorities() {
+		// Only one change can be pending. The next one is scheduled once it is enacted.
+		if <grandpa::Module<T>>::pending_change().is_some() {
+			return;
+		}
+
+		let next: Vec<(T::SessionKey, u64)> = <consensus::Module<T>>::authorities()
+			.into_iter()
+			.map(|key| (key, 1))
+			.collect();
+		if next == <grandpa::Module<T>>::grandpa_authorities() {
+			<ForcedFinalityBase<T>>::kill();
+			return;
+		}
+
+		// After an emergency replacement the old voters cannot be relied on to
+		// finalize the change, so it is forced.
+		match <grandpa::Module<T>>::schedule_change(next, Zero::zero(), Self::forced_finality_base()) {
+			Ok(()) => <ForcedFinalityBase<T>>::kill(),
+			Err(e) => {
+				runtime_io::print("Failed to schedule GRANDPA authority set change:");
+				runtime_io::print(e);
+			},
+		}
+	}
+
+	// Checks that a replacement validator set is non-empty and has no duplicate accounts.
+	fn ensure_valid_set(new_set: &[(T::AccountId, T::SessionKey)]) -> Result {
+		ensure!(!new_set.is_empty(), "Validator set cannot be empty.");
+
+		let mut accounts: Vec<&T::AccountId> = new_set.iter().map(|x| &x.0).collect();
+		accounts.sort();
+		accounts.dedup();
+		ensure!(accounts.len() == new_set.len(), "Duplicate validator in set.");
+		Ok(())
+	}
+
+	// Replaces the validators in this module, the session module and the consensus module at once.
+	// The GRANDPA voters follow in a forced change resuming from block `finalized`.
+	//
+	// Nothing is changed if the set is invalid.
+	fn replace_authorities(new_set: Vec<(T::AccountId, T::SessionKey)>, finalized: T::BlockNumber) -> Result {
+		Self::ensure_valid_set(&new_set)?;
+
+		for v in <session::Module<T>>::validators() {
+			let session_key = <Validators<T>>::take(v.clone());
+			Self::clear_proposals(v.clone(), session_key);
+			<AuthorshipStats<T>>::remove(v);
+		}
+
+		// Make the session module pick up the new keys in later sessions as well.
+		for (account_id, session_key) in new_set.iter() {
+			<session::NextKeyFor<T>>::insert(account_id, session_key);
+			<Validators<T>>::insert(account_id.clone(), session_key.clone());
+		}
+
+		let accounts: Vec<T::AccountId> = new_set.iter().map(|x| x.0.clone()).collect();
+		let keys: Vec<T::SessionKey> = new_set.into_iter().map(|x| x.1).collect();
+		<session::Module<T>>::set_validators(&accounts);
+		<session::Module<T>>::rotate_session(true, false);
+
+		// Apply the new keys right away instead of waiting for the next session.
+		<consensus::Module<T>>::set_authorities(&keys);
+		<ForcedFinalityBase<T>>::put(finalized);
+		Ok(())
+	}
+
+	// Removes the proposals and votes of a validator so that it can be proposed again.
+	fn clear_proposals(account_id: T::AccountId, session_key: T::SessionKey) {
+		let key = (account_id, session_key);
+		<PendingAdditions<T>>::remove(&key);
+		<PendingRemovals<T>>::remove(&key);
+		<AdditionKeys<T>>::mutate(|keys| keys.retain(|k| k != &key));
+		<RemovalKeys<T>>::mutate(|keys| keys.retain(|k| k != &key));
+	}
+
+	// Brings the storage up to `STORAGE_VERSION`, one layout version at a time.
+	fn migrate() {
+		let from = Self::storage_version();
+		if from >= STORAGE_VERSION {
+			return;
+		}
+
+		if from < 1 {
+			Self::migrate_to_v1();
+		}
+
+		<StorageVersion<T>>::put(STORAGE_VERSION);
+		Self::deposit_event(RawEvent::StorageMigrated(from, STORAGE_VERSION));
+	}
+
+	// Version 1 merges the `*Proposals` flags and `*Votes` lists into `Proposal` structs.
+	//
+	// Version 0 maps cannot be enumerated, so the proposals that can be found are
+	// migrated: removal proposals for the current session key of each validator.
+	// Addition proposals and removal proposals naming another key are dropped and
+	// have to be made again.
+	fn migrate_to_v1() {
+		let now = <system::Module<T>>::block_number();
+		for account_id in <session::Module<T>>::validators() {
+			let key = (account_id.clone(), Self::validators(account_id));
+			if <RemovalProposals<T>>::take(&key) {
+				let proposal = Proposal { votes: <RemovalVotes<T>>::take(&key), proposed_at: now };
+				<PendingRemovals<T>>::insert(&key, proposal);
+				<RemovalKeys<T>>::mutate(|keys| keys.push(key));
+			}
+		}
+	}
+
+	/// Whether the account is a current validator.
+	pub fn is_validator(who: &T::AccountId) -> bool {
+		<Validators<T>>::exists(who)
+	}
+
+	/// Current validator with the given session key.
+	pub fn validator_of(session_key: &T::SessionKey) -> Option<T::AccountId> {
+		<session::Module<T>>::validators()
+			.into_iter()
+			.find(|v| Self::validators(v) == *session_key)
+	}
+
+	/// Ensure an equivocation of the validator in the slot can be recorded: it is
+	/// within the window and was not recorded before.
+	pub fn ensure_new_equivocation(who: &T::AccountId, slot: u64) -> Result {
+		ensure!(Self::in_equivocation_window(slot), "Equivocation is too old.");
+
+		let slots = Self::equivocations(who);
+		ensure!(!slots.contains(&slot), "Equivocation already reported.");
+		ensure!(
+			slots.iter().filter(|slot| Self::in_equivocation_window(**slot)).count() < MAX_EQUIVOCATIONS,
+			"Too many equivocations of the validator recorded."
+		);
+		Ok(())
+	}
+
+	/// Record an equivocation of the validator in the slot, dropping the ones out of the window.
+	pub fn note_equivocation(who: &T::AccountId, slot: u64) {
+		<Equivocations<T>>::mutate(who, |slots| {
+			slots.retain(|slot| Self::in_equivocation_window(*slot));
+			slots.push(slot);
+		});
+	}
+
+	fn in_equivocation_window(slot: u64) -> bool {
+		slot.saturating_add(EQUIVOCATION_WINDOW) >= Self::last_slot()
+	}
+
+	/// Open a proposal to remove the validator, unless one is pending.
+	///
+	/// The proposal starts without votes: equivocation reports are unsigned, so there
+	/// is no validator to count as the proposer, and the removal needs the same
+	/// majority as any other.
+	pub fn open_removal_proposal(account_id: T::AccountId, session_key: T::SessionKey) {
+		let key = (account_id.clone(), session_key.clone());
+		if Self::pending_removals(&key).is_some() {
+			return;
+		}
+
+		<RemovalKeys<T>>::mutate(|keys| keys.push(key.clone()));
+		<PendingRemovals<T>>::insert(key, Proposal { votes: Vec::new(), proposed_at: <system::Module<T>>::block_number() });
+	}
+
+	/// Authorship counters of all current validators.
+	pub fn all_authorship_stats() -> Vec<(T::AccountId, ValidatorStats)> {
+		<session::Module<T>>::validators()
+			.into_iter()
+			.map(|v| (v.clone(), Self::authorship_stats(v)))
+			.collect()
+	}
+
+	// Credits the author of the previous block and charges the authors
+	// of any slots skipped before it.
+	//
+	// The slot of a block is only known once its timestamp inherent has been
+	// applied, so each block is accounted for at the start of the next one.
+	// With Aura the author of a slot is always `authorities[slot % n]`, with the
+	// authorities at the start of the block, so no seal inspection is needed.
+	fn note_authorship() {
+		let current = <consensus::Module<T>>::authorities();
+		let mut authorities = <BlockAuthorities<T>>::get();
+		if authorities != current {
+			<BlockAuthorities<T>>::put(current.clone());
+		}
+		// Until one block has been processed, the set cannot have changed.
+		if authorities.is_empty() {
+			authorities = current;
+		}
+
+		let slot_duration = <aura::Module<T>>::slot_duration();
+		let count = authorities.len() as u64;
+		if slot_duration == 0 || count == 0 {
+			return;
+		}
+
+		// The timestamp still holds the one of the previous block.
+		let slot = <timestamp::Module<T>>::now().as_() / slot_duration;
+		let last_slot = Self::last_slot();
+		// Nothing to account for at genesis.
+		if slot == 0 || slot <= last_slot {
+			return;
+		}
+
+		// Nothing has been recorded before the first block.
+		if last_slot != 0 && slot > last_slot + 1 {
+			let skipped = slot - last_slot - 1;
+			for offset in 0..rstd::cmp::min(skipped, count) {
+				// Number of skipped slots that belonged to this author.
+				let missed = skipped / count + if offset < skipped % count { 1 } else { 0 };
+				let key = &authorities[((last_slot + 1 + offset) % count) as usize];
+				if let Some(author) = Self::validator_of(key) {
+					<AuthorshipStats<T>>::mutate(author, |stats| {
+						stats.session_missed = stats.session_missed.saturating_add(missed as u32);
+						stats.total_missed = stats.total_missed.saturating_add(missed);
+					});
+				}
+			}
+		}
+
+		if let Some(author) = Self::validator_of(&authorities[(slot % count) as usize]) {
+			<AuthorshipStats<T>>::mutate(author, |stats| {
+				stats.session_authored = stats.session_authored.saturating_add(1);
+				stats.total_authored = stats.total_authored.saturating_add(1);
+			});
+		}
+
+		<LastSlot<T>>::put(slot);
+	}
+}
+
+impl<T: Trait> session::OnSessionChange<T::Moment> for Module<T> {
+	// Resets the per-session counters of the current validators.
+	fn on_session_change(_elapsed: T::Moment, _should_reward: bool) {
+		for v in <session::Module<T>>::validators() {
+			<AuthorshipStats<T>>::mutate(v, |stats| {
+				stats.session_authored = 0;
+				stats.session_missed = 0;
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::with_externalities;
+	use support::assert_ok;
+	use runtime_primitives::traits::OnInitialize;
+	use session::OnSessionChange;
+	use crate::mock::{new_test_ext, account, session_key, signed, events};
+	use crate::{Runtime, Event, System, Session, Consensus, Grandpa, Timestamp, ValidatorSet};
+
+	// Authors a block in the given slot and starts the next one, which accounts
+	// for it. Slots last 6 seconds.
+	fn author_in_slot(slot: u64) {
+		Timestamp::set_timestamp(slot * 6);
+		<ValidatorSet as OnInitialize<u64>>::on_initialize(1);
+	}
+
+	fn stats(n: u8) -> ValidatorStats {
+		ValidatorSet::authorship_stats(account(n))
+	}
+
+	#[test]
+	fn block_is_credited_to_the_slot_owner() {
+		with_externalities(&mut new_test_ext(3), || {
+			author_in_slot(10);
+
+			assert_eq!(stats(2), ValidatorStats { session_authored: 1, session_missed: 0, total_authored: 1, total_missed: 0 });
+			assert_eq!(stats(1), ValidatorStats::default());
+			assert_eq!(stats(3), ValidatorStats::default());
+			assert_eq!(ValidatorSet::last_slot(), 10);
+		});
+	}
+
+	#[test]
+	fn blocks_are_credited_by_the_authorities_they_were_authored_with() {
+		with_externalities(&mut new_test_ext(3), || {
+			Consensus::set_authorities(&[session_key(3), session_key(1), session_key(2)]);
+			author_in_slot(10);
+			assert_eq!(stats(1).total_authored, 1);
+
+			// A change within a block applies from the next block on.
+			Consensus::set_authorities(&[session_key(1), session_key(2), session_key(3)]);
+			author_in_slot(11);
+			assert_eq!(stats(2).total_authored, 1);
+			author_in_slot(12);
+			assert_eq!(stats(1).total_authored, 2);
+		});
+	}
+
+	#[test]
+	fn first_block_charges_no_missed_slots() {
+		with_externalities(&mut new_test_ext(2), || {
+			author_in_slot(1000);
+
+			assert_eq!(stats(1).total_missed + stats(2).total_missed, 0);
+		});
+	}
+
+	#[test]
+	fn skipped_slots_are_charged_to_their_owners() {
+		with_externalities(&mut new_test_ext(3), || {
+			author_in_slot(10);
+			// Slots 11 to 14 are skipped, slot 11 and 14 belong to the third validator.
+			author_in_slot(15);
+
+			assert_eq!(stats(1), ValidatorStats { session_authored: 1, session_missed: 1, total_authored: 1, total_missed: 1 });
+			assert_eq!(stats(2), ValidatorStats { session_authored: 1, session_missed: 1, total_authored: 1, total_missed: 1 });
+			assert_eq!(stats(3), ValidatorStats { session_authored: 0, session_missed: 2, total_authored: 0, total_missed: 2 });
+		});
+	}
+
+	#[test]
+	fn long_gaps_are_charged_in_bulk() {
+		with_externalities(&mut new_test_ext(2), || {
+			author_in_slot(10);
+			author_in_slot(1010);
+
+			assert_eq!(stats(1).total_missed, 499);
+			assert_eq!(stats(2).total_missed, 500);
+		});
+	}
+
+	#[test]
+	fn session_change_resets_session_counters_only() {
+		with_externalities(&mut new_test_ext(2), || {
+			author_in_slot(10);
+			author_in_slot(12);
+			<ValidatorSet as OnSessionChange<u64>>::on_session_change(0, false);
+
+			assert_eq!(stats(1), ValidatorStats { session_authored: 0, session_missed: 0, total_authored: 2, total_missed: 0 });
+			assert_eq!(stats(2), ValidatorStats { session_authored: 0, session_missed: 0, total_authored: 0, total_missed: 1 });
+		});
+	}
+
+	#[test]
+	fn emergency_set_replaces_validators_and_keys() {
+		with_externalities(&mut new_test_ext(3), || {
+			let new_set = vec![(account(4), session_key(4)), (account(5), session_key(5))];
+			assert_ok!(ValidatorSet::emergency_set_validators(new_set, 0));
+
+			assert_eq!(Session::validators(), vec![account(4), account(5)]);
+			assert_eq!(Consensus::authorities(), vec![session_key(4), session_key(5)]);
+			assert_eq!(<session::NextKeyFor<Runtime>>::get(account(5)), Some(session_key(5)));
+			assert_eq!(ValidatorSet::validators(account(4)), session_key(4));
+			assert!(!ValidatorSet::is_validator(&account(1)));
+		});
+	}
+
+	#[test]
+	fn delayed_emergency_set_is_cleared_once_applied() {
+		with_externalities(&mut new_test_ext(3), || {
+			System::set_block_number(1);
+			assert_ok!(ValidatorSet::set_emergency_delay(5));
+			assert_ok!(ValidatorSet::emergency_set_validators(vec![(account(4), session_key(4))], 0));
+
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(5);
+			assert!(ValidatorSet::pending_emergency_set().is_some());
+			assert_eq!(Session::validators(), vec![account(1), account(2), account(3)]);
+
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(6);
+			assert!(ValidatorSet::pending_emergency_set().is_none());
+			assert_eq!(Session::validators(), vec![account(4)]);
+			assert!(events().contains(&Event::validatorset(RawEvent::EmergencySetApplied(vec![account(4)]))));
+		});
+	}
+
+	#[test]
+	fn failed_emergency_set_is_dropped() {
+		with_externalities(&mut new_test_ext(3), || {
+			System::set_block_number(1);
+			let invalid = vec![(account(4), session_key(4)), (account(4), session_key(5))];
+			<PendingEmergencySet<Runtime>>::put((5, 0, invalid));
+
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(5);
+			assert!(ValidatorSet::pending_emergency_set().is_none());
+			assert_eq!(Session::validators(), vec![account(1), account(2), account(3)]);
+
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(6);
+			let failed = Event::validatorset(RawEvent::EmergencySetFailed(vec![account(4), account(4)]));
+			assert_eq!(events().into_iter().filter(|e| *e == failed).count(), 1);
+		});
+	}
+
+	// Reverts the storage to version 0, to be migrated in the next block.
+	fn downgrade() {
+		System::set_block_number(1);
+		<StorageVersion<Runtime>>::put(0);
+	}
+
+	#[test]
+	fn migrates_version_0_proposals() {
+		with_externalities(&mut new_test_ext(3), || {
+			downgrade();
+			let (remove_2, remove_3) = ((account(2), session_key(2)), (account(3), session_key(3)));
+			let remove_other = (account(3), session_key(9));
+			let add = (account(4), session_key(4));
+			for key in &[&remove_2, &remove_3, &remove_other] {
+				<RemovalProposals<Runtime>>::insert(*key, true);
+				<RemovalVotes<Runtime>>::insert(*key, vec![account(1)]);
+			}
+			<AddProposals<Runtime>>::insert(&add, true);
+			<AddVotes<Runtime>>::insert(&add, vec![account(1), account(2)]);
+
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(1);
+
+			assert_eq!(ValidatorSet::storage_version(), STORAGE_VERSION);
+			assert!(events().contains(&Event::validatorset(RawEvent::StorageMigrated(0, STORAGE_VERSION))));
+			assert_eq!(ValidatorSet::removal_keys(), vec![remove_2.clone(), remove_3.clone()]);
+			for key in &[&remove_2, &remove_3] {
+				assert_eq!(ValidatorSet::pending_removals(*key), Some(Proposal { votes: vec![account(1)], proposed_at: 1 }));
+				assert!(!<RemovalProposals<Runtime>>::exists(*key));
+				assert!(!<RemovalVotes<Runtime>>::exists(*key));
+			}
+
+			// Proposals that cannot be found are dropped.
+			assert!(ValidatorSet::pending_removals(&remove_other).is_none());
+			assert!(ValidatorSet::pending_additions(&add).is_none());
+			assert!(ValidatorSet::addition_keys().is_empty());
+
+			assert_ok!(ValidatorSet::propose_validator(signed(3), account(4), session_key(4)));
+			assert_eq!(ValidatorSet::pending_additions(&add).unwrap().votes, vec![account(3)]);
+		});
+	}
+
+	#[test]
+	fn emergency_set_forces_finality_change() {
+		with_externalities(&mut new_test_ext(3), || {
+			System::set_block_number(5);
+			assert_ok!(ValidatorSet::emergency_set_validators(vec![(account(4), session_key(4))], 3));
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(6);
+
+			let change = Grandpa::pending_change().unwrap();
+			assert_eq!(change.next_authorities, vec![(session_key(4), 1)]);
+			assert_eq!(change.forced, Some(3));
+			assert!(ValidatorSet::forced_finality_base().is_none());
+		});
+	}
+
+	#[test]
+	fn no_finality_change_is_scheduled_while_one_is_pending() {
+		with_externalities(&mut new_test_ext(3), || {
+			<session::NextKeyFor<Runtime>>::insert(account(4), session_key(4));
+			assert_ok!(ValidatorSet::add_validator(account(4), session_key(4)));
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(1);
+			assert_eq!(Grandpa::pending_change().unwrap().next_authorities.len(), 4);
+			assert_eq!(Grandpa::pending_change().unwrap().forced, None);
+
+			assert_ok!(ValidatorSet::remove_validator(account(3), session_key(3)));
+			<ValidatorSet as OnInitialize<u64>>::on_initialize(2);
+			assert_eq!(Grandpa::pending_change().unwrap().next_authorities.len(), 4);
+		});
+	}
+
+	#[test]
+	fn equivocations_are_recorded_once_within_the_window() {
+		with_externalities(&mut new_test_ext(3), || {
+			<LastSlot<Runtime>>::put(EQUIVOCATION_WINDOW + 100);
+
+			assert_ok!(ValidatorSet::ensure_new_equivocation(&account(1), 100));
+			ValidatorSet::note_equivocation(&account(1), 100);
+			assert_eq!(ValidatorSet::ensure_new_equivocation(&account(1), 100), Err("Equivocation already reported."));
+			assert_eq!(ValidatorSet::ensure_new_equivocation(&account(1), 99), Err("Equivocation is too old."));
+			assert_ok!(ValidatorSet::ensure_new_equivocation(&account(2), 100));
+
+			// Slots that left the window are pruned and cannot be reported again.
+			<LastSlot<Runtime>>::put(EQUIVOCATION_WINDOW + 200);
+			ValidatorSet::note_equivocation(&account(1), EQUIVOCATION_WINDOW + 150);
+			assert_eq!(ValidatorSet::equivocations(account(1)), vec![EQUIVOCATION_WINDOW + 150]);
+			assert_eq!(ValidatorSet::ensure_new_equivocation(&account(1), 100), Err("Equivocation is too old."));
+		});
+	}
+
+	#[test]
+	fn equivocations_of_a_validator_are_capped() {
+		with_externalities(&mut new_test_ext(3), || {
+			<LastSlot<Runtime>>::put(1000);
+			for slot in 0..MAX_EQUIVOCATIONS as u64 {
+				ValidatorSet::note_equivocation(&account(1), 1000 - slot);
+			}
+
+			assert_eq!(
+				ValidatorSet::ensure_new_equivocation(&account(1), 1001),
+				Err("Too many equivocations of the validator recorded.")
+			);
+		});
+	}
+}
@@ -0,0 +1,542 @@
+//! Selection of the local authority key.
+//!
+//! A node may hold several session keys, e.g. the current one and the one it
+//! is about to rotate to. Authoring follows the on-chain authority set and
+//! always uses the local key that is a current authority, if any.
+//!
+//! Authoring and GRANDPA voting stop for good when another node appears to
+//! author with the same key, since both nodes would otherwise keep producing
+//! competing blocks and votes.
+
+use std::{collections::BTreeMap, fs, path::PathBuf, sync::Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures::{Future, Stream};
+use log::{error, info, warn};
+use parking_lot::{Condvar, Mutex, RwLock};
+use serde_derive::Serialize;
+use primitives::{ed25519::{self, Pair}, hexdisplay::HexDisplay, Pair as PairT, Blake2Hasher};
+use runtime_primitives::{generic::BlockId, traits::ProvideRuntimeApi};
+use substrate_client::{self as client, BlockchainEvents, Client, BlockImportNotification};
+use substrate_poa_runtime::{opaque::Block, Hash, RuntimeApi};
+use consensus_authorities::AuthoritiesApi;
+use consensus_common::BlockOrigin;
+use crate::gossip::Gossip;
+use crate::key;
+use crate::equivocation::{author_of, seal_of};
+
+/// Slots of local blocks remembered to detect two blocks in the same slot.
+const SEEN_SLOTS: u64 = 256;
+
+/// A block authored with a local key that this node did not author, or a
+/// second block of a local key in the same slot.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateAuthoring {
+	/// The local key.
+	pub key: ed25519::Public,
+	/// Slot of the block.
+	pub slot: u64,
+	/// Hash of the block.
+	pub block: Hash,
+	/// The other block of the key in the same slot, if any.
+	pub other_block: Option<Hash>,
+	/// Unix timestamp of the detection, in seconds.
+	pub detected_at: u64,
+}
+
+/// Stops authoring and GRANDPA voting once a local key appears to be used by
+/// another node.
+#[derive(Clone)]
+pub struct AuthoringGuard {
+	alert: Arc<RwLock<Option<DuplicateAuthoring>>>,
+	signal: Arc<Mutex<Option<exit_future::Signal>>>,
+	exit: exit_future::Exit,
+}
+
+impl Default for AuthoringGuard {
+	fn default() -> Self {
+		let (signal, exit) = exit_future::signal();
+		AuthoringGuard {
+			alert: Default::default(),
+			signal: Arc::new(Mutex::new(Some(signal))),
+			exit,
+		}
+	}
+}
+
+impl AuthoringGuard {
+	/// The duplicate authoring that stopped authoring, if any.
+	pub fn alert(&self) -> Option<DuplicateAuthoring> {
+		self.alert.read().clone()
+	}
+
+	/// Resolves when the guard trips, to stop the tasks that vote with the local keys.
+	pub fn on_trip(&self) -> exit_future::Exit {
+		self.exit.clone()
+	}
+
+	fn is_tripped(&self) -> bool {
+		self.alert.read().is_some()
+	}
+
+	fn trip(&self, alert: DuplicateAuthoring) {
+		match alert.other_block {
+			Some(other) => error!(target: "poa", "!!! Blocks {} and {} were both authored in slot {} with the local key {} !!!",
+				other, alert.block, alert.slot, alert.key),
+			None => error!(target: "poa", "!!! Block {} of slot {} was authored with the local key {} by another node !!!",
+				alert.block, alert.slot, alert.key),
+		}
+		error!(target: "poa", "!!! Authoring and GRANDPA voting are stopped until restart. Make sure the key is only used by one node !!!");
+		*self.alert.write() = Some(alert);
+		if let Some(signal) = self.signal.lock().take() {
+			signal.fire();
+		}
+	}
+}
+
+/// Authoring tasks that are running, so that shutdown can wait for them.
+#[derive(Clone, Default)]
+pub struct AuthoringTasks {
+	// Number of running tasks, notified when it drops to zero.
+	running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl AuthoringTasks {
+	/// Count `task` as running until it completes or is dropped.
+	pub fn track<F: Future>(&self, task: F) -> impl Future<Item=F::Item, Error=F::Error> {
+		*self.running.0.lock() += 1;
+		let running = Running(self.running.clone());
+		task.then(move |result| {
+			drop(running);
+			result
+		})
+	}
+
+	/// Wait for all tasks to finish, for at most `timeout`. Returns whether they did.
+	pub fn wait(&self, timeout: Duration) -> bool {
+		let deadline = Instant::now() + timeout;
+		let (ref running, ref finished) = *self.running;
+		let mut running = running.lock();
+		while *running > 0 {
+			if finished.wait_until(&mut running, deadline).timed_out() {
+				return *running == 0;
+			}
+		}
+		true
+	}
+}
+
+// Decrements the running task count when dropped.
+struct Running(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for Running {
+	fn drop(&mut self) {
+		let (ref running, ref finished) = *self.0;
+		let mut running = running.lock();
+		*running -= 1;
+		if *running == 0 {
+			finished.notify_all();
+		}
+	}
+}
+
+/// Keys the node can author with: `key` and the keys of the keystore.
+///
+/// `key` comes first, since keys given with `--key` are not written to the keystore.
+/// The keystore is read again by `reload`, so keys inserted while the node runs are
+/// picked up.
+#[derive(Clone, Default)]
+pub struct LocalKeys {
+	key: Option<Arc<Pair>>,
+	keystore_path: Option<String>,
+	password: String,
+	keys: Arc<RwLock<Vec<Arc<Pair>>>>,
+}
+
+impl LocalKeys {
+	/// Read the keys of the keystore at `keystore_path`, along with `key`.
+	pub fn new(key: Option<Arc<Pair>>, keystore_path: &str, password: &str) -> Self {
+		let keys = LocalKeys {
+			key,
+			keystore_path: Some(keystore_path.to_owned()),
+			password: password.to_owned(),
+			keys: Default::default(),
+		};
+		keys.reload();
+		keys
+	}
+
+	/// The current keys.
+	pub fn get(&self) -> Vec<Arc<Pair>> {
+		self.keys.read().clone()
+	}
+
+	/// Public keys of the current keys.
+	pub fn publics(&self) -> Vec<ed25519::Public> {
+		self.keys.read().iter().map(|key| key.public()).collect()
+	}
+
+	/// Read the keystore again. Returns whether the keys changed.
+	pub fn reload(&self) -> bool {
+		let keys = match self.keystore_path {
+			Some(ref path) => load_keys(self.key.clone(), path, &self.password),
+			None => return false,
+		};
+		let publics: Vec<_> = keys.iter().map(|key| key.public()).collect();
+		if publics == self.publics() {
+			return false;
+		}
+		*self.keys.write() = keys;
+		true
+	}
+}
+
+// Keystore files are read with `key::read_session_key`, since keys inserted with
+// `key insert` may be secret URIs, which the Substrate keystore does not read.
+fn load_keys(key: Option<Arc<Pair>>, keystore_path: &str, password: &str) -> Vec<Arc<Pair>> {
+	let mut keys: Vec<Arc<Pair>> = key.into_iter().collect();
+
+	let mut paths: Vec<PathBuf> = match fs::read_dir(keystore_path) {
+		Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+		Err(e) => {
+			warn!(target: "poa", "Unable to open keystore {}: {}", keystore_path, e);
+			return keys;
+		}
+	};
+	paths.sort();
+
+	for path in paths {
+		// Key files are named after the hex public key.
+		let name = match path.file_name().and_then(|name| name.to_str()) {
+			Some(name) if name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit()) => name.to_lowercase(),
+			_ => continue,
+		};
+		let pair = match key::read_session_key(&path, password) {
+			Ok(pair) => pair,
+			Err(e) => {
+				warn!(target: "poa", "Unable to load key {}: {}", name, e);
+				continue;
+			}
+		};
+		if format!("{}", HexDisplay::from(&pair.public().as_ref())) != name {
+			warn!(target: "poa", "Key file {} holds another key, is the password right?", path.display());
+			continue;
+		}
+		if keys.iter().any(|key| key.public() == pair.public()) {
+			continue;
+		}
+		keys.push(Arc::new(pair));
+	}
+
+	keys
+}
+
+/// The first of `keys` that is an authority at the best block.
+pub fn authority_key<B, E>(client: &Client<B, E, Block, RuntimeApi>, keys: &[Arc<Pair>]) -> Option<Arc<Pair>> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let at = BlockId::hash(client.info().ok()?.chain.best_hash);
+	let authorities = client.runtime_api().authorities(&at).ok()?;
+	keys.iter().find(|key| authorities.contains(&key.public())).cloned()
+}
+
+/// Author with whichever of `keys` is a current authority.
+///
+/// `start` starts authoring with the given key until the given exit fires. It is
+/// called again each time the authority key changes, after the previous
+/// authoring task was told to stop. Authoring stops for good when `guard` trips.
+///
+/// The keystore is read again each time the authority set changes. The GRANDPA
+/// voter keeps `grandpa_key` until restart, which is logged when it differs from
+/// the authority key.
+pub fn follow_authorities<B, E, S>(
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	keys: LocalKeys,
+	grandpa_key: Option<ed25519::Public>,
+	gossip: Gossip,
+	guard: AuthoringGuard,
+	slot_duration: u64,
+	mut start: S,
+) -> impl Future<Item=(), Error=()> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+	S: FnMut(Arc<Pair>, exit_future::Exit) -> Result<(), String>,
+{
+	let mut active: Option<(ed25519::Public, exit_future::Signal)> = None;
+	let mut last_authorities = None;
+	let mut seen_slots = BTreeMap::new();
+	// Blocks of earlier slots may have been authored by this node before a restart.
+	let first_slot = now_secs() / slot_duration;
+
+	let mut update = move |client: &Client<B, E, Block, RuntimeApi>, notification: Option<BlockImportNotification<Block>>| {
+		let at = match notification {
+			Some(notification) => {
+				if !guard.is_tripped() {
+					if let Some(alert) = duplicate_of(client, &notification, &keys.get(), first_slot, &mut seen_slots) {
+						guard.trip(alert);
+						if let Some((_, signal)) = active.take() {
+							signal.fire();
+							gossip.clear_local_key();
+						}
+					}
+				}
+				if !notification.is_new_best || guard.is_tripped() {
+					return;
+				}
+				BlockId::hash(notification.hash)
+			},
+			None => match client.info() {
+				Ok(info) => BlockId::hash(info.chain.best_hash),
+				Err(_) => return,
+			},
+		};
+
+		let authorities = match client.runtime_api().authorities(&at) {
+			Ok(authorities) => authorities,
+			Err(e) => {
+				warn!(target: "poa", "Unable to read authorities: {:?}", e);
+				return;
+			}
+		};
+
+		if last_authorities.as_ref() != Some(&authorities) {
+			if last_authorities.is_some() && keys.reload() {
+				info!(target: "poa", "Authority set changed, reloaded {} local keys", keys.get().len());
+			}
+			last_authorities = Some(authorities.clone());
+		}
+
+		let keys = keys.get();
+		let key = keys.iter().find(|key| authorities.contains(&key.public())).cloned();
+		if active.as_ref().map(|(public, _)| public) == key.as_ref().map(|key| key.public()).as_ref() {
+			return;
+		}
+
+		if let Some((public, signal)) = active.take() {
+			info!(target: "poa", "Key {} is no longer an authority, stopping authoring", public);
+			signal.fire();
+			gossip.clear_local_key();
+		}
+
+		match key {
+			Some(key) => {
+				let (signal, exit) = exit_future::signal();
+				match start(key.clone(), exit) {
+					Ok(()) => {
+						info!(target: "poa", "Authoring with key {}", key.public());
+						if let Some(grandpa_key) = grandpa_key.as_ref().filter(|grandpa_key| **grandpa_key != key.public()) {
+							warn!(target: "poa", "GRANDPA still votes with key {}, restart the node to vote with {}",
+								grandpa_key, key.public());
+						}
+						gossip.set_local_key(key.clone());
+						active = Some((key.public(), signal));
+					},
+					Err(e) => warn!(target: "poa", "Unable to start authoring with key {}: {}", key.public(), e),
+				}
+			},
+			None => info!(target: "poa", "None of the {} local keys is a current authority", keys.len()),
+		}
+	};
+
+	update(&client, None);
+
+	client.import_notification_stream()
+		.for_each(move |notification| {
+			update(&client, Some(notification));
+			Ok(())
+		})
+}
+
+// The duplicate authoring shown by an imported block of a local key, if any: the
+// block was not authored by this node, or another block of the key has the same slot.
+//
+// `seen_slots` holds the blocks of local keys of the last `SEEN_SLOTS` slots.
+fn duplicate_of<B, E>(
+	client: &Client<B, E, Block, RuntimeApi>,
+	notification: &BlockImportNotification<Block>,
+	keys: &[Arc<Pair>],
+	first_slot: u64,
+	seen_slots: &mut BTreeMap<u64, Hash>,
+) -> Option<DuplicateAuthoring> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let (slot, _, _) = seal_of(&notification.header)?;
+	if slot < first_slot {
+		return None;
+	}
+
+	let author = author_of(client, &notification.header, slot)?;
+	let key = keys.iter().find(|key| key.public() == author)?;
+
+	let other_block = seen_slots.insert(slot, notification.hash).filter(|other| *other != notification.hash);
+	*seen_slots = seen_slots.split_off(&slot.saturating_sub(SEEN_SLOTS));
+	if notification.origin == BlockOrigin::Own && other_block.is_none() {
+		return None;
+	}
+
+	Some(DuplicateAuthoring {
+		key: key.public(),
+		slot,
+		block: notification.hash,
+		other_block,
+		detected_at: now_secs(),
+	})
+}
+
+fn now_secs() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{path::Path, thread};
+	use parity_codec::Encode;
+	use primitives::H256;
+	use runtime_primitives::traits::{Block as BlockT, Header as HeaderT, Digest, DigestItemFor};
+	use consensus::CompatibleDigestItem;
+	use crate::service::test_client;
+
+	fn pair(suri: &str) -> Pair {
+		Pair::from_string(suri, None).unwrap()
+	}
+
+	fn keystore(name: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("substrate-poa-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&path);
+		fs::create_dir_all(&path).unwrap();
+		path
+	}
+
+	// Writes `suri` to a key file named after the public key of `name_of`.
+	fn insert(keystore: &Path, name_of: &str, suri: &str) {
+		let name = format!("{}", HexDisplay::from(&pair(name_of).public().as_ref()));
+		fs::write(keystore.join(name), serde_json::to_string(suri).unwrap()).unwrap();
+	}
+
+	#[test]
+	fn reload_reads_keys_inserted_later() {
+		let path = keystore("reload");
+		let given = Arc::new(pair("//Charlie"));
+		let keys = LocalKeys::new(Some(given.clone()), path.to_str().unwrap(), "");
+		assert_eq!(keys.publics(), vec![given.public()]);
+		assert!(!keys.reload());
+
+		insert(&path, "//Bob", "//Bob");
+		fs::write(path.join("README"), "not a key").unwrap();
+		assert!(keys.reload());
+		assert_eq!(keys.publics(), vec![given.public(), pair("//Bob").public()]);
+		assert!(!keys.reload());
+
+		// Files named after another key are skipped.
+		insert(&path, "//Alice", "//Dave");
+		assert!(!keys.reload());
+
+		fs::remove_dir_all(&path).unwrap();
+	}
+
+	#[test]
+	fn authoring_starts_with_the_authority_key() {
+		let path = keystore("follow");
+		insert(&path, "//Alice", "//Alice");
+		let keys = LocalKeys::new(Some(Arc::new(pair("//Charlie"))), path.to_str().unwrap(), "");
+		fs::remove_dir_all(&path).unwrap();
+
+		let started = Arc::new(Mutex::new(Vec::new()));
+		let record = started.clone();
+		let _authoring = follow_authorities(
+			Arc::new(test_client()),
+			keys,
+			Some(pair("//Alice").public()),
+			Gossip::default(),
+			AuthoringGuard::default(),
+			6,
+			move |key: Arc<Pair>, _| {
+				record.lock().push(key.public());
+				Ok(())
+			},
+		);
+
+		// Charlie comes first but is not an authority of the local testnet.
+		assert_eq!(*started.lock(), vec![pair("//Alice").public()]);
+	}
+
+	#[test]
+	fn nothing_is_authored_without_an_authority_key() {
+		let started = Arc::new(Mutex::new(Vec::new()));
+		let record = started.clone();
+		let _authoring = follow_authorities(
+			Arc::new(test_client()),
+			LocalKeys::new(Some(Arc::new(pair("//Charlie"))), "/nonexistent", ""),
+			None,
+			Gossip::default(),
+			AuthoringGuard::default(),
+			6,
+			move |key: Arc<Pair>, _| {
+				record.lock().push(key.public());
+				Ok(())
+			},
+		);
+
+		assert!(started.lock().is_empty());
+	}
+
+	// Import notification of a block on top of `parent`, sealed by `key` in `slot`.
+	fn sealed(parent: Hash, extrinsics_root: u8, slot: u64, key: &Pair, origin: BlockOrigin) -> BlockImportNotification<Block> {
+		let mut header = <<Block as BlockT>::Header as HeaderT>::new(1, H256::from([extrinsics_root; 32]), Default::default(), parent, Default::default());
+		let signature = key.sign(&(slot, header.hash()).encode()[..]);
+		header.digest_mut().push(<DigestItemFor<Block> as CompatibleDigestItem<Pair>>::aura_seal(slot, signature));
+		BlockImportNotification { hash: header.hash(), origin, header, is_new_best: true }
+	}
+
+	#[test]
+	fn foreign_block_of_a_local_key_is_a_duplicate() {
+		let client = test_client();
+		let genesis = client.info().unwrap().chain.genesis_hash;
+		let (alice, bob) = (Arc::new(pair("//Alice")), Arc::new(pair("//Bob")));
+		let mut seen_slots = BTreeMap::new();
+
+		// Slot 4 belongs to Alice, the first authority of the local testnet.
+		let own = sealed(genesis, 1, 4, &alice, BlockOrigin::Own);
+		assert!(duplicate_of(&client, &own, &[alice.clone()], 0, &mut seen_slots).is_none());
+		let foreign = sealed(genesis, 2, 6, &alice, BlockOrigin::NetworkBroadcast);
+		assert!(duplicate_of(&client, &foreign, &[bob.clone()], 0, &mut seen_slots).is_none());
+		// Blocks of slots from before the node started are ignored.
+		assert!(duplicate_of(&client, &foreign, &[alice.clone()], 7, &mut seen_slots).is_none());
+
+		let alert = duplicate_of(&client, &foreign, &[bob, alice.clone()], 0, &mut seen_slots).unwrap();
+		assert_eq!((alert.key, alert.slot, alert.block, alert.other_block), (alice.public(), 6, foreign.hash, None));
+	}
+
+	#[test]
+	fn two_blocks_of_a_local_key_in_a_slot_are_duplicates() {
+		let client = test_client();
+		let genesis = client.info().unwrap().chain.genesis_hash;
+		let alice = Arc::new(pair("//Alice"));
+		let mut seen_slots = BTreeMap::new();
+
+		let first = sealed(genesis, 1, 4, &alice, BlockOrigin::Own);
+		assert!(duplicate_of(&client, &first, &[alice.clone()], 0, &mut seen_slots).is_none());
+		// The same block imported again is no duplicate.
+		assert!(duplicate_of(&client, &first, &[alice.clone()], 0, &mut seen_slots).is_none());
+
+		let second = sealed(genesis, 2, 4, &alice, BlockOrigin::Own);
+		let alert = duplicate_of(&client, &second, &[alice.clone()], 0, &mut seen_slots).unwrap();
+		assert_eq!((alert.slot, alert.block, alert.other_block), (4, second.hash, Some(first.hash)));
+	}
+
+	#[test]
+	fn wait_returns_once_tasks_are_done() {
+		let tasks = AuthoringTasks::default();
+		assert!(tasks.wait(Duration::from_secs(0)));
+
+		let task = tasks.track(futures::future::empty::<(), ()>());
+		assert!(!tasks.wait(Duration::from_millis(10)));
+
+		let done = thread::spawn(move || drop(task));
+		assert!(tasks.wait(Duration::from_secs(10)));
+		done.join().unwrap();
+	}
+}
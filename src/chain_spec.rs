@@ -1,12 +1,20 @@
-use primitives::{ed25519, sr25519, Pair};
+use std::path::PathBuf;
+use primitives::{ed25519, sr25519, Pair, crypto::Ss58Codec};
 use substrate_poa_runtime::{
 	AccountId, GenesisConfig, ConsensusConfig, TimestampConfig, BalancesConfig,
-	SudoConfig, IndicesConfig, ValidatorSetConfig, SessionConfig,
+	SudoConfig, IndicesConfig, ValidatorSetConfig, SessionConfig, CouncilConfig,
+	MultisigConfig, UpgradeConfig, AllowlistConfig, RateLimitConfig, NodeAuthConfig,
+	GrandpaConfig, MisbehaviorConfig, VERSION, code_version,
 };
 use substrate_service;
+use serde_derive::{Serialize, Deserialize};
+use telemetry::TelemetryEndpoints;
 
 use ed25519::Public as AuthorityId;
 
+/// Wasm blob of the runtime, set as the code of the genesis block.
+const WASM_CODE: &[u8] = include_bytes!("../runtime/wasm/target/wasm32-unknown-unknown/release/substrate_poa_runtime_wasm.compact.wasm");
+
 /// Specialized `ChainSpec`. This is a specialization of the general Substrate ChainSpec type.
 pub type ChainSpec = substrate_service::ChainSpec<GenesisConfig>;
 
@@ -19,8 +27,261 @@ pub enum Alternative {
 	Development,
 	/// Whatever the current runtime is, with simple Alice/Bob auths.
 	LocalTestnet,
+	/// Whatever the current runtime is, with the network described in the file named by `STAGING_CONFIG_VAR`.
+	Staging,
+}
+
+/// Environment variable with the path of the staging network description.
+pub const STAGING_CONFIG_VAR: &str = "POA_STAGING_CONFIG";
+
+/// Description of the `staging` network, read from a JSON file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StagingConfig {
+	/// Validators as SS58 addresses of their controller (sr25519) and session (ed25519) keys.
+	pub authorities: Vec<(String, String)>,
+	/// SS58 addresses of the accounts endowed at genesis, besides the controllers.
+	#[serde(default)]
+	pub endowed_accounts: Vec<String>,
+	/// SS58 address of the sudo key.
+	pub root_key: String,
+	/// Multiaddresses of the boot nodes.
+	#[serde(default)]
+	pub boot_nodes: Vec<String>,
+	/// Telemetry endpoints and their verbosity.
+	#[serde(default)]
+	pub telemetry_endpoints: Vec<(String, u8)>,
+	/// Time and currency parameters.
+	#[serde(default)]
+	pub params: ChainParams,
+}
+
+impl StagingConfig {
+	/// Read the description from the file named by `STAGING_CONFIG_VAR`.
+	pub fn load() -> Result<Self, String> {
+		let path = std::env::var(STAGING_CONFIG_VAR)
+			.map_err(|_| format!("Set {} to the path of the staging config", STAGING_CONFIG_VAR))?;
+		Self::from_file(&path)
+	}
+
+	/// Read the description from a JSON file.
+	pub fn from_file(path: &str) -> Result<Self, String> {
+		let file = std::fs::File::open(path)
+			.map_err(|e| format!("Unable to open staging config {}: {}", path, e))?;
+		serde_json::from_reader(file).map_err(|e| format!("Invalid staging config {}: {}", path, e))
+	}
+
+	fn authorities(&self) -> Result<Vec<(AccountId, AuthorityId)>, String> {
+		if self.authorities.is_empty() {
+			return Err("Staging config needs at least one authority".into());
+		}
+		self.authorities.iter()
+			.map(|(controller, session)| Ok((parse_account(controller)?, parse_authority(session)?)))
+			.collect()
+	}
+}
+
+/// Description of a consortium network, from which its chain spec is generated.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NetworkDescription {
+	/// Human readable name of the chain.
+	pub name: String,
+	/// Identifier of the chain, also used for its database directory.
+	pub id: String,
+	/// Initial validators.
+	pub validators: Vec<ValidatorDescription>,
+	/// SS58 addresses of the accounts endowed at genesis, besides the controllers.
+	#[serde(default)]
+	pub endowed_accounts: Vec<String>,
+	/// SS58 address of the sudo key. Exclusive with `council_threshold`.
+	#[serde(default)]
+	pub sudo_key: Option<String>,
+	/// Start in council mode with this approval threshold. Exclusive with `sudo_key`.
+	#[serde(default)]
+	pub council_threshold: Option<u32>,
+	/// Multiaddresses of the boot nodes.
+	#[serde(default)]
+	pub boot_nodes: Vec<String>,
+	/// Telemetry endpoints and their verbosity.
+	#[serde(default)]
+	pub telemetry_endpoints: Vec<(String, u8)>,
+	/// Time and currency parameters.
+	#[serde(default)]
+	pub params: ChainParams,
+}
+
+/// Keys of a validator, as SS58 addresses.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ValidatorDescription {
+	/// Controller key (sr25519).
+	pub controller: String,
+	/// Session key (ed25519).
+	pub session_key: String,
+}
+
+impl NetworkDescription {
+	/// Read a description from a file, as TOML if its name ends in `.toml` and as JSON otherwise.
+	pub fn from_file(path: &str) -> Result<Self, String> {
+		let data = std::fs::read_to_string(path)
+			.map_err(|e| format!("Unable to read network description {}: {}", path, e))?;
+		if path.ends_with(".toml") {
+			toml::from_str(&data).map_err(|e| format!("Invalid network description {}: {}", path, e))
+		} else {
+			serde_json::from_str(&data).map_err(|e| format!("Invalid network description {}: {}", path, e))
+		}
+	}
+
+	/// Build the chain spec of the network.
+	pub fn build(self) -> Result<ChainSpec, String> {
+		let NetworkDescription {
+			name, id, validators, endowed_accounts, sudo_key, council_threshold,
+			boot_nodes, telemetry_endpoints, params,
+		} = self;
+		params.validate()?;
+
+		if validators.is_empty() {
+			return Err("At least one validator is needed".into());
+		}
+		let authorities = validators.iter()
+			.map(|v| Ok((parse_account(&v.controller)?, parse_authority(&v.session_key)?)))
+			.collect::<Result<Vec<_>, String>>()?;
+		let controllers: Vec<_> = authorities.iter().map(|a| a.0.clone()).collect();
+		if let Some(i) = first_duplicate(&controllers) {
+			return Err(format!("Duplicate validator controller {}", validators[i].controller));
+		}
+		let session_keys: Vec<_> = authorities.iter().map(|a| a.1.clone()).collect();
+		if let Some(i) = first_duplicate(&session_keys) {
+			return Err(format!("Duplicate validator session key {}", validators[i].session_key));
+		}
+
+		let endowed_accounts = endowed_accounts.iter()
+			.map(|account| parse_account(account))
+			.collect::<Result<Vec<_>, _>>()?;
+		if let Some(i) = first_duplicate(&boot_nodes) {
+			return Err(format!("Duplicate boot node {}", boot_nodes[i]));
+		}
+
+		// In council mode the sudo key is removed in the first block, so any key will do.
+		let (root_key, council_threshold) = match (sudo_key, council_threshold) {
+			(Some(key), None) => (parse_account(&key)?, None),
+			(None, Some(threshold)) if threshold as usize > authorities.len() => {
+				return Err(format!("Council threshold {} exceeds the {} validators", threshold, authorities.len()));
+			},
+			(None, Some(threshold)) => (controllers[0].clone(), Some(threshold)),
+			_ => return Err("Exactly one of sudoKey and councilThreshold must be set".into()),
+		};
+
+		let genesis = move || {
+			let mut genesis = testnet_genesis(authorities.clone(), endowed_accounts.clone(), root_key.clone(), &params);
+			if let Some(threshold) = council_threshold {
+				genesis.council = Some(CouncilConfig {
+					enabled: true,
+					threshold,
+					proposal_lifetime: 24 * params.minutes() * 60,
+				});
+			}
+			genesis
+		};
+		check_genesis(&genesis())?;
+
+		let telemetry = if telemetry_endpoints.is_empty() {
+			None
+		} else {
+			Some(TelemetryEndpoints::new(telemetry_endpoints))
+		};
+		Ok(ChainSpec::from_genesis(&name, &id, genesis, boot_nodes, telemetry, None, None, None))
+	}
+}
+
+fn parse_account(s: &str) -> Result<AccountId, String> {
+	AccountId::from_ss58check(s).map_err(|e| format!("Invalid account address {}: {:?}", s, e))
+}
+
+fn parse_authority(s: &str) -> Result<AuthorityId, String> {
+	AuthorityId::from_ss58check(s).map_err(|e| format!("Invalid session key {}: {:?}", s, e))
+}
+
+/// Time and currency parameters of a chain.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ChainParams {
+	/// Target time between two blocks, in seconds. This is the Aura slot duration.
+	pub secs_per_block: u64,
+	/// Length of a session, in blocks.
+	pub session_length: u64,
+	/// Minimum balance an account must keep to exist.
+	pub existential_deposit: u64,
+	/// Base fee of every transaction.
+	pub transaction_base_fee: u64,
+	/// Fee per byte of a transaction.
+	pub transaction_byte_fee: u64,
+	/// Fee of a transfer.
+	pub transfer_fee: u64,
+	/// Fee of a transfer creating a new account.
+	pub creation_fee: u64,
+}
+
+impl Default for ChainParams {
+	// As configured in Substrate node
+	// https://github.com/paritytech/substrate/blob/master/node/cli/src/chain_spec.rs
+	fn default() -> Self {
+		let secs_per_block = 6;
+		ChainParams {
+			secs_per_block,
+			session_length: 5 * 60 / secs_per_block, // 5 minutes
+			existential_deposit: 500,
+			transaction_base_fee: 1,
+			transaction_byte_fee: 0,
+			transfer_fee: 0,
+			creation_fee: 0,
+		}
+	}
 }
 
+impl ChainParams {
+	/// Read parameters from a JSON file. Missing fields keep their default value.
+	pub fn from_json_file(path: &str) -> Result<Self, String> {
+		let file = std::fs::File::open(path)
+			.map_err(|e| format!("Unable to open chain parameters {}: {}", path, e))?;
+		let params: ChainParams = serde_json::from_reader(file)
+			.map_err(|e| format!("Invalid chain parameters {}: {}", path, e))?;
+		params.validate()?;
+		Ok(params)
+	}
+
+	/// Reject parameters the runtime cannot work with.
+	pub fn validate(&self) -> Result<(), String> {
+		// The timestamp module counts seconds and Aura slots are twice its minimum period.
+		if self.secs_per_block < 2 || self.secs_per_block % 2 != 0 {
+			return Err("Block time must be an even number of seconds, at least 2".into());
+		}
+		if self.secs_per_block > 60 {
+			return Err("Block time must not exceed one minute".into());
+		}
+		// Durations in minutes and hours are derived from the blocks per minute.
+		if 60 % self.secs_per_block != 0 {
+			return Err("Block time must divide one minute evenly".into());
+		}
+		if self.session_length == 0 {
+			return Err("Session length must be at least one block".into());
+		}
+		if u128::from(self.existential_deposit) >= ENDOWMENT {
+			return Err(format!("Existential deposit must be lower than the endowment of {}", ENDOWMENT));
+		}
+		Ok(())
+	}
+
+	// Blocks per minute. `validate` ensures the division is exact.
+	fn minutes(&self) -> u64 {
+		60 / self.secs_per_block
+	}
+}
+
+/// Balance of each endowed account at genesis.
+const ENDOWMENT: u128 = 1 << 60;
+
 fn authority_key(s: &str) -> AuthorityId {
 	ed25519::Pair::from_string(&format!("//{}", s), None)
 		.expect("static values are valid; qed")
@@ -36,16 +297,23 @@ fn account_key(s: &str) -> AccountId {
 impl Alternative {
 	/// Get an actual chain config from one of the alternatives.
 	pub(crate) fn load(self) -> Result<ChainSpec, String> {
+		self.load_with(ChainParams::default())
+	}
+
+	/// Get an actual chain config from one of the alternatives with the given parameters.
+	pub(crate) fn load_with(self, params: ChainParams) -> Result<ChainSpec, String> {
+		params.validate()?;
 		Ok(match self {
 			Alternative::Development => ChainSpec::from_genesis(
 				"Development",
 				"dev",
-				|| testnet_genesis(vec![
-					authority_key("Alice")
+				move || testnet_genesis(vec![
+					(account_key("Alice"), authority_key("Alice")),
 				], vec![
 					account_key("Alice")
 				],
-					account_key("Alice")
+					account_key("Alice"),
+					&params,
 				),
 				vec![],
 				None,
@@ -56,9 +324,9 @@ impl Alternative {
 			Alternative::LocalTestnet => ChainSpec::from_genesis(
 				"Local Testnet",
 				"local_testnet",
-				|| testnet_genesis(vec![
-					authority_key("Alice"),
-					authority_key("Bob"),
+				move || testnet_genesis(vec![
+					(account_key("Alice"), authority_key("Alice")),
+					(account_key("Bob"), authority_key("Bob")),
 				], vec![
 					account_key("Alice"),
 					account_key("Bob"),
@@ -68,6 +336,7 @@ impl Alternative {
 					account_key("Ferdie"),
 				],
 					account_key("Alice"),
+					&params,
 				),
 				vec![],
 				None,
@@ -75,6 +344,37 @@ impl Alternative {
 				None,
 				None
 			),
+			Alternative::Staging => {
+				let config = StagingConfig::load()?;
+				let authorities = config.authorities()?;
+				let endowed_accounts = config.endowed_accounts.iter()
+					.map(|account| parse_account(account))
+					.collect::<Result<Vec<_>, _>>()?;
+				let root_key = parse_account(&config.root_key)?;
+				let telemetry = if config.telemetry_endpoints.is_empty() {
+					None
+				} else {
+					Some(TelemetryEndpoints::new(config.telemetry_endpoints.clone()))
+				};
+				let params = config.params;
+				params.validate()?;
+				check_genesis(&testnet_genesis(authorities.clone(), endowed_accounts.clone(), root_key.clone(), &params))?;
+				ChainSpec::from_genesis(
+					"PoA Staging",
+					"staging",
+					move || testnet_genesis(
+						authorities.clone(),
+						endowed_accounts.clone(),
+						root_key.clone(),
+						&params,
+					),
+					config.boot_nodes,
+					telemetry,
+					None,
+					None,
+					None
+				)
+			},
 		})
 	}
 
@@ -82,53 +382,362 @@ impl Alternative {
 		match s {
 			"dev" => Some(Alternative::Development),
 			"" | "local" => Some(Alternative::LocalTestnet),
+			"staging" => Some(Alternative::Staging),
 			_ => None,
 		}
 	}
 }
 
-fn testnet_genesis(_initial_authorities: Vec<AuthorityId>, _endowed_accounts: Vec<AccountId>, root_key: AccountId) -> GenesisConfig {
-	// As configured in Substrate node
-	// https://github.com/paritytech/substrate/blob/master/node/cli/src/chain_spec.rs
-	const SECS_PER_BLOCK: u64 = 6;
-	const MINUTES: u64 = 60 / SECS_PER_BLOCK;
-	
-	// Defining authorities again and not using the ones passed in the initial_authorities parameter.
-	// This is to easily reuse them across genesis configs of several modules (see below).
-	// Each item is a tuple of controller key (sr25519) and session key (ed25519).
-	let authorities = vec![(account_key("Alice"), authority_key("Alice")), (account_key("Bob"), authority_key("Bob"))];
+/// Load a chain spec from a JSON file.
+///
+/// Specs with a human readable genesis are checked for consistency. Raw specs
+/// can only be checked by the runtime when the genesis block is built.
+pub(crate) fn from_json_file(path: &str) -> Result<ChainSpec, String> {
+	let data = std::fs::read(path).map_err(|e| format!("Unable to read chain spec {}: {}", path, e))?;
+	let json: serde_json::Value = serde_json::from_slice(&data)
+		.map_err(|e| format!("Invalid chain spec {}: {}", path, e))?;
+
+	if let Some(runtime) = json.get("genesis").and_then(|genesis| genesis.get("runtime")) {
+		let genesis: GenesisConfig = serde_json::from_value(runtime.clone())
+			.map_err(|e| format!("Invalid genesis in chain spec {}: {}", path, e))?;
+		check_genesis(&genesis).map_err(|e| format!("Invalid genesis in chain spec {}: {}", path, e))?;
+	}
+
+	ChainSpec::from_json_file(PathBuf::from(path))
+}
+
+/// Check that the genesis sections describing the validators agree with each other.
+pub fn check_genesis(genesis: &GenesisConfig) -> Result<(), String> {
+	let validators = &genesis.validatorset.as_ref().ok_or("Missing validator set")?.validators;
+	if validators.is_empty() {
+		return Err("Validator set is empty".into());
+	}
+	let controllers: Vec<AccountId> = validators.iter().map(|v| v.0.clone()).collect();
+	let session_keys: Vec<AuthorityId> = validators.iter().map(|v| v.1.clone()).collect();
+	if first_duplicate(&controllers).is_some() || first_duplicate(&session_keys).is_some() {
+		return Err("Validator set contains duplicate keys".into());
+	}
+
+	let consensus = genesis.consensus.as_ref().ok_or("Missing consensus authorities")?;
+	if consensus.authorities != session_keys {
+		return Err("Consensus authorities do not match the validator session keys".into());
+	}
+	if let Some(ref grandpa) = genesis.grandpa {
+		if grandpa.authorities.iter().map(|a| &a.0).ne(session_keys.iter()) {
+			return Err("GRANDPA authorities do not match the validator session keys".into());
+		}
+	}
+
+	let session = genesis.session.as_ref().ok_or("Missing session config")?;
+	if session.validators != controllers || session.keys != *validators {
+		return Err("Session validators do not match the validator set".into());
+	}
+	if session.session_length == 0 {
+		return Err("Session length must be at least one block".into());
+	}
+	if genesis.timestamp.as_ref().map_or(true, |t| t.minimum_period == 0) {
+		return Err("Minimum period must be at least one second".into());
+	}
+	if let Some(ref ratelimit) = genesis.ratelimit {
+		if (ratelimit.enabled || ratelimit.feeless) && (ratelimit.max_transactions == 0 || ratelimit.window_length == 0) {
+			return Err("Rate limiting needs a non-zero limit and window length".into());
+		}
+	}
+
+	Ok(())
+}
+
+// Index of the first item that equals an earlier one.
+fn first_duplicate<T: PartialEq>(items: &[T]) -> Option<usize> {
+	items.iter().enumerate().position(|(i, item)| items[..i].contains(item))
+}
+
+/// Each item of `initial_authorities` is a tuple of controller key (sr25519) and session key (ed25519).
+fn testnet_genesis(initial_authorities: Vec<(AccountId, AuthorityId)>, endowed_accounts: Vec<AccountId>, root_key: AccountId, params: &ChainParams) -> GenesisConfig {
+	let minutes = params.minutes();
+	let hours = minutes * 60;
+
+	let authorities = initial_authorities;
+
+	// Controllers are always endowed, so that validators can pay for their votes.
+	let mut endowed_accounts = endowed_accounts;
+	for (controller, _) in &authorities {
+		if !endowed_accounts.contains(controller) {
+			endowed_accounts.push(controller.clone());
+		}
+	}
 
 	GenesisConfig {
 		consensus: Some(ConsensusConfig {
-			code: include_bytes!("../runtime/wasm/target/wasm32-unknown-unknown/release/substrate_poa_runtime_wasm.compact.wasm").to_vec(),
+			code: WASM_CODE.to_vec(),
 			authorities: authorities.iter().map(|x| x.1.clone()).collect() // session keys from authorities vec declared above
 		}),
 		system: None,
 		timestamp: Some(TimestampConfig {
-			minimum_period: SECS_PER_BLOCK / 2, // due to the nature of aura the slots are 2*period
+			minimum_period: params.secs_per_block / 2, // due to the nature of aura the slots are 2*period
+		}),
+		grandpa: Some(GrandpaConfig {
+			authorities: authorities.iter().map(|x| (x.1.clone(), 1)).collect(), // session keys from authorities vec declared above
 		}),
 		indices: Some(IndicesConfig {
-			ids: authorities.iter().map(|x| x.0.clone()).collect(), // controller keys from authorities vec declared above
+			ids: endowed_accounts.clone(), // endowed accounts including the controller keys
 		}),
 		session: Some(SessionConfig {
 			validators: authorities.iter().map(|x| x.0.clone()).collect(), // controller keys from authorities vec declared above
-			session_length: 5 * MINUTES,
+			session_length: params.session_length,
 			keys: authorities.clone() // authorities vec declared above
 		}),
 		balances: Some(BalancesConfig {
-			transaction_base_fee: 1,
-			transaction_byte_fee: 0,
-			existential_deposit: 500,
-			transfer_fee: 0,
-			creation_fee: 0,
-			balances: authorities.iter().map(|x| (x.0.clone(), 1 << 60)).collect(), // controller keys from authorities vec declared above
+			transaction_base_fee: params.transaction_base_fee.into(),
+			transaction_byte_fee: params.transaction_byte_fee.into(),
+			existential_deposit: params.existential_deposit.into(),
+			transfer_fee: params.transfer_fee.into(),
+			creation_fee: params.creation_fee.into(),
+			balances: endowed_accounts.iter().map(|x| (x.clone(), ENDOWMENT)).collect(), // endowed accounts including the controller keys
 			vesting: vec![],
 		}),
 		sudo: Some(SudoConfig {
 			key: root_key,
 		}),
 		validatorset: Some(ValidatorSetConfig {
-			validators: authorities // authorities vec declared above
+			validators: authorities, // authorities vec declared above
+			emergency_delay: 0,
+		}),
+		council: Some(CouncilConfig {
+			enabled: false,
+			threshold: 0,
+			proposal_lifetime: 24 * hours,
+		}),
+		multisig: Some(MultisigConfig {
+			proposal_lifetime: 24 * hours,
+		}),
+		upgrade: Some(UpgradeConfig {
+			upgrade_delay: 10 * minutes,
+		}),
+		allowlist: Some(AllowlistConfig {
+			enabled: false,
+			allowed: vec![],
 		}),
+		ratelimit: Some(RateLimitConfig {
+			enabled: false,
+			feeless: false,
+			max_transactions: 10,
+			window_length: 1,
+		}),
+		nodeauth: Some(NodeAuthConfig {
+			enabled: false,
+			authorized_peers: vec![],
+		}),
+		misbehavior: Some(MisbehaviorConfig {
+			propose_removal: false,
+		}),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn wasm_code_declares_its_version() {
+		let version = (VERSION.spec_name.as_bytes().to_vec(), VERSION.spec_version);
+		assert_eq!(code_version(WASM_CODE), Some(version));
+	}
+
+	#[test]
+	fn block_time_must_divide_a_minute() {
+		let params = |secs_per_block| ChainParams { secs_per_block, ..Default::default() };
+
+		for secs in &[2, 4, 6, 10, 12, 20, 30, 60] {
+			assert_eq!(params(*secs).validate(), Ok(()));
+			assert_eq!(params(*secs).minutes() * secs, 60);
+		}
+		for secs in &[0, 3, 8, 14, 62] {
+			assert!(params(*secs).validate().is_err());
+		}
+	}
+
+	fn local_genesis() -> GenesisConfig {
+		testnet_genesis(vec![
+			(account_key("Alice"), authority_key("Alice")),
+			(account_key("Bob"), authority_key("Bob")),
+		], vec![
+			account_key("Charlie"),
+		],
+			account_key("Alice"),
+			&ChainParams::default(),
+		)
+	}
+
+	#[test]
+	fn testnet_genesis_sections_agree() {
+		let genesis = local_genesis();
+		assert_eq!(check_genesis(&genesis), Ok(()));
+
+		let keys = vec![authority_key("Alice"), authority_key("Bob")];
+		let controllers = vec![account_key("Alice"), account_key("Bob")];
+		assert_eq!(genesis.consensus.as_ref().unwrap().authorities, keys);
+		assert_eq!(genesis.grandpa.as_ref().unwrap().authorities, vec![(keys[0].clone(), 1), (keys[1].clone(), 1)]);
+		assert_eq!(genesis.session.as_ref().unwrap().validators, controllers);
+		assert_eq!(
+			genesis.validatorset.as_ref().unwrap().validators,
+			vec![(controllers[0].clone(), keys[0].clone()), (controllers[1].clone(), keys[1].clone())],
+		);
+	}
+
+	#[test]
+	fn testnet_genesis_endows_controllers() {
+		let genesis = local_genesis();
+		let endowed: Vec<AccountId> = genesis.balances.as_ref().unwrap().balances.iter().map(|b| b.0.clone()).collect();
+
+		assert_eq!(endowed, vec![account_key("Charlie"), account_key("Alice"), account_key("Bob")]);
+		assert_eq!(genesis.indices.as_ref().unwrap().ids, endowed);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn check_genesis_rejects_disagreeing_sections() {
+		let mut genesis = local_genesis();
+		genesis.consensus.as_mut().unwrap().authorities.reverse();
+		assert!(check_genesis(&genesis).is_err());
+
+		let mut genesis = local_genesis();
+		genesis.grandpa.as_mut().unwrap().authorities.pop();
+		assert!(check_genesis(&genesis).is_err());
+
+		let mut genesis = local_genesis();
+		genesis.session.as_mut().unwrap().keys[1].1 = authority_key("Charlie");
+		assert!(check_genesis(&genesis).is_err());
+
+		let mut genesis = local_genesis();
+		genesis.validatorset.as_mut().unwrap().validators[1].0 = account_key("Alice");
+		assert!(check_genesis(&genesis).is_err());
+	}
+
+	fn validator(name: &str) -> ValidatorDescription {
+		ValidatorDescription {
+			controller: account_key(name).to_ss58check(),
+			session_key: authority_key(name).to_ss58check(),
+		}
+	}
+
+	fn description() -> NetworkDescription {
+		NetworkDescription {
+			name: "Consortium".into(),
+			id: "consortium".into(),
+			validators: vec![validator("Alice"), validator("Bob")],
+			endowed_accounts: vec![account_key("Charlie").to_ss58check()],
+			sudo_key: Some(account_key("Alice").to_ss58check()),
+			council_threshold: None,
+			boot_nodes: vec!["/ip4/127.0.0.1/tcp/30333/p2p/QmSk5HQbn6LhUwDiNMseVUjuRYhEtYj4aUZ6WfWoGURpdV".into()],
+			telemetry_endpoints: vec![],
+			params: ChainParams::default(),
+		}
+	}
+
+	fn build_error(description: NetworkDescription) -> String {
+		match description.build() {
+			Ok(_) => panic!("invalid description accepted"),
+			Err(e) => e,
+		}
+	}
+
+	#[test]
+	fn network_description_builds() {
+		assert!(description().build().is_ok());
+
+		let mut council = description();
+		council.sudo_key = None;
+		council.council_threshold = Some(2);
+		assert!(council.build().is_ok());
+	}
+
+	#[test]
+	fn network_description_rejects_duplicates() {
+		let mut twice = description();
+		twice.validators.push(validator("Alice"));
+		assert!(build_error(twice).starts_with("Duplicate validator controller"));
+
+		let mut shared_key = description();
+		shared_key.validators[1].session_key = shared_key.validators[0].session_key.clone();
+		assert!(build_error(shared_key).starts_with("Duplicate validator session key"));
+
+		let mut boot_nodes = description();
+		boot_nodes.boot_nodes.push(boot_nodes.boot_nodes[0].clone());
+		assert!(build_error(boot_nodes).starts_with("Duplicate boot node"));
+	}
+
+	#[test]
+	fn network_description_rejects_invalid_keys() {
+		let mut controller = description();
+		controller.validators[0].controller = "5Alice".into();
+		assert!(build_error(controller).starts_with("Invalid account address 5Alice"));
+
+		let mut session_key = description();
+		session_key.validators[0].session_key = "".into();
+		assert!(build_error(session_key).starts_with("Invalid session key"));
+
+		let mut endowed = description();
+		endowed.endowed_accounts.push("Charlie".into());
+		assert!(build_error(endowed).starts_with("Invalid account address Charlie"));
+
+		let mut sudo = description();
+		sudo.sudo_key = Some("root".into());
+		assert!(build_error(sudo).starts_with("Invalid account address root"));
+
+		let mut none = description();
+		none.validators.clear();
+		assert_eq!(build_error(none), "At least one validator is needed");
+	}
+
+	#[test]
+	fn network_description_needs_either_sudo_or_council() {
+		let mut both = description();
+		both.council_threshold = Some(2);
+		assert_eq!(build_error(both), "Exactly one of sudoKey and councilThreshold must be set");
+
+		let mut neither = description();
+		neither.sudo_key = None;
+		assert_eq!(build_error(neither), "Exactly one of sudoKey and councilThreshold must be set");
+
+		let mut too_high = description();
+		too_high.sudo_key = None;
+		too_high.council_threshold = Some(3);
+		assert_eq!(build_error(too_high), "Council threshold 3 exceeds the 2 validators");
+	}
+
+	#[test]
+	fn staging_config_is_read_from_the_variable() {
+		let path = std::env::temp_dir().join(format!("substrate-poa-staging-{}.json", std::process::id()));
+		let config = format!(
+			r#"{{"authorities": [["{}", "{}"]], "rootKey": "{}", "params": {{"secsPerBlock": 6}}}}"#,
+			account_key("Alice").to_ss58check(), authority_key("Alice").to_ss58check(), account_key("Bob").to_ss58check(),
+		);
+		std::fs::write(&path, config).unwrap();
+
+		std::env::remove_var(STAGING_CONFIG_VAR);
+		assert!(StagingConfig::load().unwrap_err().starts_with("Set POA_STAGING_CONFIG"));
+
+		std::env::set_var(STAGING_CONFIG_VAR, &path);
+		let loaded = StagingConfig::load();
+		std::env::remove_var(STAGING_CONFIG_VAR);
+		std::fs::remove_file(&path).unwrap();
+
+		let config = loaded.unwrap();
+		assert_eq!(config.authorities(), Ok(vec![(account_key("Alice"), authority_key("Alice"))]));
+		assert_eq!(config.root_key, account_key("Bob").to_ss58check());
+		assert_eq!(config.params.secs_per_block, 6);
+	}
+
+	#[test]
+	fn malformed_staging_config_is_rejected() {
+		let path = std::env::temp_dir().join(format!("substrate-poa-malformed-{}.json", std::process::id()));
+		std::fs::write(&path, r#"{"authorities": [], "rootKey": "#).unwrap();
+		let path = path.to_str().unwrap().to_owned();
+
+		let error = StagingConfig::from_file(&path).unwrap_err();
+		std::fs::remove_file(&path).unwrap();
+		assert!(error.starts_with(&format!("Invalid staging config {}", path)));
+
+		assert!(StagingConfig::from_file("/nonexistent/staging.json").unwrap_err().starts_with("Unable to open staging config"));
+	}
+}
@@ -1,13 +1,219 @@
 use crate::service;
-use futures::{future, Future, sync::oneshot};
-use std::cell::RefCell;
+use futures::{future, Future, Stream};
+use std::{io, sync::{Arc, mpsc}, thread, time::Duration};
 use tokio::runtime::Runtime;
+#[cfg(unix)]
+use tokio_signal::unix::{Signal, SIGTERM};
 pub use substrate_cli::{VersionInfo, IntoExit, error};
-use substrate_cli::{informant, parse_and_execute, NoCustom};
+use substrate_cli::{informant, parse_and_execute, impl_augment_clap, GetLogFilter};
 use substrate_service::{ServiceFactory, Roles as ServiceRoles};
 use crate::chain_spec;
 use std::ops::Deref;
-use log::info;
+use std::path::Path;
+use log::{info, warn};
+use structopt::StructOpt;
+use crate::seal::SealMode;
+use crate::authoring::AuthoringTasks;
+use crate::health::Thresholds;
+use crate::http;
+use crate::reload::{self, Reloader};
+use crate::key::KeyCmd;
+use crate::extrinsic::SignExtrinsicCmd;
+
+/// Additional arguments of the `run` command.
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct CustomArgs {
+	/// Serve the PoA RPC methods (e.g. `poa_validatorStats`) on this local port.
+	#[structopt(long = "poa-rpc-port", value_name = "PORT")]
+	poa_rpc_port: Option<u16>,
+
+	/// Accept connections from peers that are not authorized on chain. Only allowed on the dev chain.
+	#[structopt(long = "disable-peer-authorization")]
+	disable_peer_authorization: bool,
+
+	/// Seal blocks as soon as a transaction arrives (`instant`) or on `engine_createBlock` calls (`manual`)
+	/// instead of running Aura. Only allowed on the dev chain.
+	#[structopt(long = "dev-seal", value_name = "MODE", raw(possible_values = "&[\"instant\", \"manual\"]"))]
+	dev_seal: Option<SealMode>,
+
+	/// Serve Prometheus metrics on `/metrics` at this port.
+	#[structopt(long = "metrics-port", value_name = "PORT")]
+	metrics_port: Option<u16>,
+
+	/// Listen for metrics scrapes on all interfaces instead of only the local one.
+	#[structopt(long = "metrics-external")]
+	metrics_external: bool,
+
+	/// Serve the `/health` and `/ready` probes at this port.
+	#[structopt(long = "health-port", value_name = "PORT")]
+	health_port: Option<u16>,
+
+	/// Listen for probes on all interfaces instead of only the local one.
+	#[structopt(long = "health-external")]
+	health_external: bool,
+
+	/// Number of blocks the node may be behind its peers and still be ready.
+	#[structopt(long = "health-max-blocks-behind", value_name = "BLOCKS", default_value = "5")]
+	health_max_blocks_behind: u64,
+
+	/// Number of peers below which the `/ready` probe fails.
+	#[structopt(long = "health-min-peers", value_name = "PEERS", default_value = "1")]
+	health_min_peers: usize,
+
+	/// Only report the node ready when one of its keys is a current authority.
+	#[structopt(long = "health-require-authority")]
+	health_require_authority: bool,
+
+	/// Seconds to wait for running tasks to finish on shutdown before exiting anyway.
+	#[structopt(long = "shutdown-timeout", value_name = "SECS", default_value = "30")]
+	shutdown_timeout: u64,
+
+	/// TOML file with the log level and reserved peers, applied at startup and reloaded on SIGHUP.
+	///
+	/// The log level can only be lowered from the one set with `--log`.
+	#[structopt(long = "reload-file", value_name = "FILE")]
+	reload_file: Option<String>,
+
+	/// Read the block time, session length, existential deposit and fees of the dev or local chain from a JSON file.
+	#[structopt(long = "chain-params", value_name = "FILE")]
+	chain_params: Option<String>,
+
+	/// Block time of the dev or local chain in seconds. Overrides `--chain-params`.
+	#[structopt(long = "block-time", value_name = "SECS")]
+	block_time: Option<u64>,
+
+	/// Session length of the dev or local chain in blocks. Overrides `--chain-params`.
+	#[structopt(long = "session-length", value_name = "BLOCKS")]
+	session_length: Option<u64>,
+
+	/// Existential deposit of the dev or local chain. Overrides `--chain-params`.
+	#[structopt(long = "existential-deposit", value_name = "BALANCE")]
+	existential_deposit: Option<u64>,
+}
+
+impl CustomArgs {
+	// Chain parameters given on the command line, if any.
+	fn chain_params(&self) -> Result<Option<chain_spec::ChainParams>, String> {
+		if self.chain_params.is_none() && self.block_time.is_none()
+			&& self.session_length.is_none() && self.existential_deposit.is_none()
+		{
+			return Ok(None);
+		}
+
+		let mut params = match self.chain_params {
+			Some(ref path) => chain_spec::ChainParams::from_json_file(path)?,
+			None => Default::default(),
+		};
+		if let Some(block_time) = self.block_time {
+			params.secs_per_block = block_time;
+		}
+		if let Some(session_length) = self.session_length {
+			params.session_length = session_length;
+		}
+		if let Some(existential_deposit) = self.existential_deposit {
+			params.existential_deposit = existential_deposit;
+		}
+		params.validate()?;
+		Ok(Some(params))
+	}
+}
+
+impl_augment_clap!(CustomArgs);
+
+// Chain parameters are part of the genesis block, so a database created with other
+// parameters, or with none, cannot be reused. The parameters a database was created
+// with are recorded next to it.
+fn check_database_params(database_path: &str, params: Option<&chain_spec::ChainParams>) -> Result<(), String> {
+	let record = Path::new(database_path).with_file_name("chain_params.json");
+	if !Path::new(database_path).exists() {
+		return match params {
+			Some(params) => serde_json::to_vec_pretty(params)
+				.map_err(|e| e.to_string())
+				.and_then(|data| {
+					if let Some(dir) = record.parent() {
+						std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+					}
+					std::fs::write(&record, data).map_err(|e| e.to_string())
+				})
+				.map_err(|e| format!("Unable to record chain parameters in {}: {}", record.display(), e)),
+			None => match std::fs::remove_file(&record) {
+				Err(ref e) if e.kind() != io::ErrorKind::NotFound => Err(format!("Unable to remove {}: {}", record.display(), e)),
+				_ => Ok(()),
+			},
+		};
+	}
+
+	let recorded = match std::fs::File::open(&record) {
+		Ok(file) => Some(serde_json::from_reader::<_, chain_spec::ChainParams>(file)
+			.map_err(|e| format!("Invalid chain parameters record {}: {}", record.display(), e))?),
+		Err(_) => None,
+	};
+	if recorded.as_ref() != params {
+		return Err(format!(
+			"The database in {} was created with other chain parameters. Pass the same parameters, or remove it with `purge-chain` first.",
+			database_path,
+		));
+	}
+	Ok(())
+}
+
+/// Subcommands of the node besides the ones provided by Substrate.
+#[derive(Clone, Debug, StructOpt)]
+pub enum CustomSubcommands {
+	/// Generate the chain spec of a consortium network from a TOML or JSON description.
+	#[structopt(name = "generate-spec")]
+	GenerateSpec(GenerateSpecCmd),
+
+	/// Generate, inspect and insert keys.
+	#[structopt(name = "key")]
+	Key(KeyCmd),
+
+	/// Sign a validator set call offline and print it as hex.
+	#[structopt(name = "sign-extrinsic")]
+	SignExtrinsic(SignExtrinsicCmd),
+}
+
+impl GetLogFilter for CustomSubcommands {
+	fn get_log_filter(&self) -> Option<String> {
+		None
+	}
+}
+
+/// The `generate-spec` command.
+#[derive(Clone, Debug, StructOpt)]
+pub struct GenerateSpecCmd {
+	/// Network description, read as TOML if the file name ends in `.toml` and as JSON otherwise.
+	#[structopt(value_name = "FILE")]
+	input: String,
+
+	/// Output the genesis as raw storage, as distributed to the nodes of the network.
+	#[structopt(long = "raw")]
+	raw: bool,
+
+	/// Block time in seconds. Overrides the description.
+	#[structopt(long = "block-time", value_name = "SECS")]
+	block_time: Option<u64>,
+
+	/// Session length in blocks. Overrides the description.
+	#[structopt(long = "session-length", value_name = "BLOCKS")]
+	session_length: Option<u64>,
+}
+
+impl GenerateSpecCmd {
+	fn run(self) -> Result<(), String> {
+		let mut description = chain_spec::NetworkDescription::from_file(&self.input)?;
+		if let Some(block_time) = self.block_time {
+			description.params.secs_per_block = block_time;
+		}
+		if let Some(session_length) = self.session_length {
+			description.params.session_length = session_length;
+		}
+
+		let json = description.build()?.to_json(self.raw)?;
+		println!("{}", json);
+		Ok(())
+	}
+}
 
 /// Parse command line arguments into service configuration.
 pub fn run<I, T, E>(args: I, exit: E, version: VersionInfo) -> error::Result<()> where
@@ -15,9 +221,60 @@ pub fn run<I, T, E>(args: I, exit: E, version: VersionInfo) -> error::Result<()>
 	T: Into<std::ffi::OsString> + Clone,
 	E: IntoExit,
 {
-	parse_and_execute::<service::Factory, NoCustom, NoCustom, _, _, _, _, _>(
+	let subcommand = parse_and_execute::<service::Factory, CustomSubcommands, CustomArgs, _, _, _, _, _>(
 		load_spec, &version, "substrate-node", args, exit,
-	 	|exit, _custom_args, config| {
+	 	|exit, custom_args: CustomArgs, mut config| {
+			if custom_args.disable_peer_authorization && config.chain_spec.id() != "dev" {
+				return Err("--disable-peer-authorization is only allowed on the dev chain".into());
+			}
+			if custom_args.dev_seal.is_some() && config.chain_spec.id() != "dev" {
+				return Err("--dev-seal is only allowed on the dev chain".into());
+			}
+			if custom_args.dev_seal == Some(SealMode::Manual) && custom_args.poa_rpc_port.is_none() {
+				return Err("--dev-seal=manual requires --poa-rpc-port to serve engine_createBlock".into());
+			}
+			let params = custom_args.chain_params()?;
+			// Build the chain spec before recording the parameters, so that nothing
+			// is written for a chain they cannot be used with.
+			let chain_spec = match params {
+				Some(ref params) => {
+					let alternative = match config.chain_spec.id() {
+						"dev" => chain_spec::Alternative::Development,
+						"local_testnet" => chain_spec::Alternative::LocalTestnet,
+						_ => return Err("Chain parameters can only be set for the dev and local chains".into()),
+					};
+					Some(alternative.load_with(params.clone())?)
+				},
+				None => None,
+			};
+			check_database_params(&config.database_path, params.as_ref())?;
+			if let Some(chain_spec) = chain_spec {
+				config.chain_spec = chain_spec;
+			}
+			config.custom.poa_rpc_port = custom_args.poa_rpc_port;
+			config.custom.disable_peer_authorization = custom_args.disable_peer_authorization;
+			config.custom.dev_seal = custom_args.dev_seal;
+			config.custom.metrics_addr = custom_args.metrics_port
+				.map(|port| http::listen_addr(port, custom_args.metrics_external));
+			config.custom.health_addr = custom_args.health_port
+				.map(|port| http::listen_addr(port, custom_args.health_external));
+			config.custom.health_thresholds = Thresholds {
+				max_blocks_behind: custom_args.health_max_blocks_behind,
+				min_peers: custom_args.health_min_peers,
+				require_authority: custom_args.health_require_authority,
+			};
+			if let Some(ref path) = custom_args.reload_file {
+				reload::ReloadConfig::from_file(path)?;
+			}
+			let (authoring_pause, pause) = exit_future::signal();
+			config.custom.authoring_pause = Some(pause);
+			let shutdown = Shutdown {
+				timeout: Duration::from_secs(custom_args.shutdown_timeout),
+				authoring_pause,
+				authoring_tasks: config.custom.authoring_tasks.clone(),
+				reload_file: custom_args.reload_file.clone(),
+			};
+
 			info!("{}", version.name);
 			info!("  version {}", config.full_version());
 			info!("  by {}, 2017, 2018", version.author);
@@ -30,29 +287,47 @@ pub fn run<I, T, E>(args: I, exit: E, version: VersionInfo) -> error::Result<()>
 				ServiceRoles::LIGHT => run_until_exit(
 					runtime,
 				 	service::Factory::new_light(config, executor).map_err(|e| format!("{:?}", e))?,
-					exit
+					exit,
+					shutdown,
 				),
 				_ => run_until_exit(
 					runtime,
 					service::Factory::new_full(config, executor).map_err(|e| format!("{:?}", e))?,
-					exit
+					exit,
+					shutdown,
 				),
 			}.map_err(|e| format!("{:?}", e))
 		}
-	).map_err(Into::into).map(|_| ())
+	)?;
+
+	match subcommand {
+		Some(CustomSubcommands::GenerateSpec(cmd)) => cmd.run().map_err(Into::into),
+		Some(CustomSubcommands::Key(cmd)) => cmd.run().map_err(Into::into),
+		Some(CustomSubcommands::SignExtrinsic(cmd)) => cmd.run().map_err(Into::into),
+		None => Ok(()),
+	}
 }
 
-fn load_spec(id: &str) -> Result<Option<chain_spec::ChainSpec>, String> {
+pub(crate) fn load_spec(id: &str) -> Result<Option<chain_spec::ChainSpec>, String> {
 	Ok(match chain_spec::Alternative::from(id) {
 		Some(spec) => Some(spec.load()?),
-		None => None,
+		None => Some(chain_spec::from_json_file(id)?),
 	})
 }
 
+// Shutdown and reload settings of the node process.
+struct Shutdown {
+	timeout: Duration,
+	authoring_pause: exit_future::Signal,
+	authoring_tasks: AuthoringTasks,
+	reload_file: Option<String>,
+}
+
 fn run_until_exit<T, C, E>(
 	mut runtime: Runtime,
 	service: T,
 	e: E,
+	shutdown: Shutdown,
 ) -> error::Result<()>
 	where
 		T: Deref<Target=substrate_service::Service<C>>,
@@ -64,31 +339,77 @@ fn run_until_exit<T, C, E>(
 	let executor = runtime.executor();
 	informant::start(&service, exit.clone(), executor.clone());
 
+	// SIGHUP would otherwise terminate the node when no reload file is given.
+	let reloading = reload::reload_on_hangup(Reloader::new(shutdown.reload_file, service.network()));
+	executor.spawn(reloading.select(exit.clone()).then(|_| Ok(())));
+
 	let _ = runtime.block_on(e.into_exit());
+
+	// Stop authoring first, so that no block is left half-imported.
+	info!("Shutting down, pausing authoring");
+	shutdown.authoring_pause.fire();
+	if !shutdown.authoring_tasks.wait(shutdown.timeout) {
+		warn!("Authoring still running after {} seconds, shutting down anyway", shutdown.timeout.as_secs());
+	}
 	exit_send.fire();
 
 	// we eagerly drop the service so that the internal exit future is fired,
 	// but we need to keep holding a reference to the global telemetry guard
 	let _telemetry = service.telemetry();
+	let client = Arc::downgrade(&service.client());
 	drop(service);
+
+	// The database is flushed once the last task holding the client is done.
+	let (done_send, done) = mpsc::channel();
+	thread::spawn(move || {
+		let _ = runtime.shutdown_on_idle().wait();
+		let _ = done_send.send(());
+	});
+	if done.recv_timeout(shutdown.timeout).is_err() {
+		warn!("Tasks still running after {} seconds, exiting anyway", shutdown.timeout.as_secs());
+	}
+	if client.upgrade().is_some() {
+		warn!("Database still in use, it may not be flushed");
+	} else {
+		info!("Database flushed");
+	}
 	Ok(())
 }
 
-// handles ctrl-c
+/// Exits the node on SIGINT or SIGTERM, or on Ctrl-C outside of Unix.
 pub struct Exit;
 impl IntoExit for Exit {
-	type Exit = future::MapErr<oneshot::Receiver<()>, fn(oneshot::Canceled) -> ()>;
+	type Exit = Box<dyn Future<Item=(), Error=()> + Send>;
 	fn into_exit(self) -> Self::Exit {
-		// can't use signal directly here because CtrlC takes only `Fn`.
-		let (exit_send, exit) = oneshot::channel();
+		let interrupt = first_signal("SIGINT", tokio_signal::ctrl_c().flatten_stream());
 
-		let exit_send_cell = RefCell::new(Some(exit_send));
-		ctrlc::set_handler(move || {
-			if let Some(exit_send) = exit_send_cell.try_borrow_mut().expect("signal handler not reentrant; qed").take() {
-				exit_send.send(()).expect("Error sending exit notification");
-			}
-		}).expect("Error setting Ctrl-C handler");
-
-		exit.map_err(drop)
+		Box::new(interrupt.select(terminate()).then(|_| Ok(())))
 	}
 }
+
+#[cfg(unix)]
+fn terminate() -> impl Future<Item=(), Error=()> {
+	first_signal("SIGTERM", Signal::new(SIGTERM).flatten_stream())
+}
+
+// There is no SIGTERM outside of Unix.
+#[cfg(not(unix))]
+fn terminate() -> impl Future<Item=(), Error=()> {
+	future::empty()
+}
+
+// Resolves on the first signal of `signals`, never if the handler cannot be installed.
+fn first_signal<S>(name: &'static str, signals: S) -> impl Future<Item=(), Error=()> where
+	S: Stream<Error=io::Error>,
+{
+	signals.into_future().then(move |result| match result {
+		Ok(_) => {
+			info!("Received {}", name);
+			future::Either::A(future::ok(()))
+		},
+		Err((e, _)) => {
+			warn!("Unable to handle {}: {}", name, e);
+			future::Either::B(future::empty())
+		},
+	})
+}
@@ -0,0 +1,185 @@
+//! Detection and reporting of equivocations.
+//!
+//! An authority equivocates when it signs two different blocks for the same
+//! Aura slot. Nodes that import both blocks submit the two seals as an unsigned
+//! extrinsic, and the `Misbehavior` runtime module records the offense.
+
+use std::{collections::BTreeMap, sync::Arc};
+use futures::{Future, Stream};
+use log::{info, warn};
+use parity_codec::{Encode, Decode};
+use primitives::{ed25519::Pair, Blake2Hasher};
+use runtime_primitives::{generic::BlockId, traits::{Block as BlockT, Header as HeaderT, DigestItemFor, ProvideRuntimeApi}};
+use substrate_client::{self as client, BlockchainEvents, Client};
+use substrate_poa_runtime::{
+	opaque::Block, AuthorityId, AuthoritySignature, Call, EquivocationProof, Hash,
+	MisbehaviorCall, RuntimeApi, UncheckedExtrinsic,
+};
+use consensus::CompatibleDigestItem;
+use consensus_authorities::AuthoritiesApi;
+use transaction_pool::txpool::{ChainApi, Pool};
+
+/// Number of slots for which the seals of imported blocks are kept.
+const KEEP_SLOTS: u64 = 256;
+
+/// Slot, signature and pre-seal hash of a block, from its Aura seal.
+pub fn seal_of(header: &<Block as BlockT>::Header) -> Option<(u64, AuthoritySignature, Hash)> {
+	let mut header = header.clone();
+	let seal = header.digest_mut().pop()?;
+	let (slot, signature) = <DigestItemFor<Block> as CompatibleDigestItem<Pair>>::as_aura_seal(&seal)?;
+	Some((slot, signature.clone(), header.hash()))
+}
+
+/// Authority expected to author the slot on top of the parent of `header`.
+pub fn author_of<B, E>(
+	client: &Client<B, E, Block, RuntimeApi>,
+	header: &<Block as BlockT>::Header,
+	slot: u64,
+) -> Option<AuthorityId> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let authorities = client.runtime_api().authorities(&BlockId::hash(*header.parent_hash())).ok()?;
+	if authorities.is_empty() {
+		return None;
+	}
+	Some(authorities[(slot % authorities.len() as u64) as usize].clone())
+}
+
+/// Submit a report to `pool` for each equivocation among the imported blocks.
+pub fn report_equivocations<B, E, A>(
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	pool: Arc<Pool<A>>,
+) -> impl Future<Item=(), Error=()> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+	A: ChainApi<Block=Block>,
+{
+	// Seals of the imported blocks per slot.
+	let mut seen: BTreeMap<u64, Vec<(AuthorityId, Hash, AuthoritySignature)>> = BTreeMap::new();
+
+	client.import_notification_stream().for_each(move |notification| {
+		let header = &notification.header;
+		let proof = seal_of(header).and_then(|(slot, signature, pre_hash)| {
+			let author = author_of(&client, header, slot)?;
+			check_seal(&mut seen, author, slot, pre_hash, signature)
+		});
+
+		if let Some(proof) = proof {
+			info!(target: "poa", "Authority {} equivocated in slot {}, reporting", proof.authority, proof.slot);
+			submit(&pool, notification.hash, proof);
+		}
+		Ok(())
+	})
+}
+
+// Remembers the seal and returns a proof if the author sealed another block in the slot.
+fn check_seal(
+	seen: &mut BTreeMap<u64, Vec<(AuthorityId, Hash, AuthoritySignature)>>,
+	author: AuthorityId,
+	slot: u64,
+	pre_hash: Hash,
+	signature: AuthoritySignature,
+) -> Option<EquivocationProof<AuthorityId, AuthoritySignature, Hash>> {
+	*seen = seen.split_off(&slot.saturating_sub(KEEP_SLOTS));
+
+	let seals = seen.entry(slot).or_insert_with(Vec::new);
+	if seals.iter().any(|(a, h, _)| *a == author && *h == pre_hash) {
+		return None;
+	}
+	let earlier = seals.iter().find(|(a, _, _)| *a == author).cloned();
+	seals.push((author.clone(), pre_hash, signature.clone()));
+
+	earlier.map(|(_, first_hash, first_signature)| EquivocationProof {
+		authority: author,
+		slot,
+		first: (first_hash, first_signature),
+		second: (pre_hash, signature),
+	})
+}
+
+fn submit<A>(pool: &Pool<A>, at: Hash, proof: EquivocationProof<AuthorityId, AuthoritySignature, Hash>) where
+	A: ChainApi<Block=Block>,
+{
+	let extrinsic = UncheckedExtrinsic::new_unsigned(Call::Misbehavior(MisbehaviorCall::report_equivocation(proof)));
+	let opaque = match Decode::decode(&mut &extrinsic.encode()[..]) {
+		Some(opaque) => opaque,
+		None => return,
+	};
+
+	if let Err(e) = pool.submit_one(&BlockId::hash(at), opaque) {
+		warn!(target: "poa", "Unable to submit equivocation report: {:?}", e);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use primitives::Pair as PairT;
+	use runtime_primitives::traits::Digest;
+	use crate::service::test_client;
+
+	fn header(parent_hash: Hash) -> <Block as BlockT>::Header {
+		HeaderT::new(1, Default::default(), Default::default(), parent_hash, Default::default())
+	}
+
+	fn seal(header: &mut <Block as BlockT>::Header, slot: u64, key: &Pair) -> AuthoritySignature {
+		let signature = key.sign(&(slot, header.hash()).encode()[..]);
+		header.digest_mut().push(<DigestItemFor<Block> as CompatibleDigestItem<Pair>>::aura_seal(slot, signature.clone()));
+		signature
+	}
+
+	#[test]
+	fn seal_of_returns_the_pre_seal_hash() {
+		let mut header = header(Default::default());
+		let pre_hash = header.hash();
+		let signature = seal(&mut header, 7, &Pair::from_seed(&[1; 32]));
+
+		assert_eq!(seal_of(&header), Some((7, signature, pre_hash)));
+		assert_eq!(seal_of(&self::header(Default::default())), None);
+	}
+
+	#[test]
+	fn author_of_follows_the_authorities_of_the_parent() {
+		let client = test_client();
+		let genesis = client.info().unwrap().chain.genesis_hash;
+		let authorities = client.runtime_api().authorities(&BlockId::hash(genesis)).unwrap();
+		assert_eq!(authorities.len(), 2);
+
+		assert_eq!(author_of(&client, &header(genesis), 4), Some(authorities[0].clone()));
+		assert_eq!(author_of(&client, &header(genesis), 5), Some(authorities[1].clone()));
+		assert_eq!(author_of(&client, &header(Default::default()), 4), None);
+	}
+
+	#[test]
+	fn check_seal_reports_two_blocks_in_a_slot() {
+		let key = Pair::from_seed(&[1; 32]);
+		let sign = |hash: Hash| key.sign(hash.as_ref());
+		let (a, b) = (Hash::from([1; 32]), Hash::from([2; 32]));
+		let mut seen = BTreeMap::new();
+
+		assert_eq!(check_seal(&mut seen, key.public(), 10, a, sign(a)), None);
+		// The same block imported again, or by another author, is no equivocation.
+		assert_eq!(check_seal(&mut seen, key.public(), 10, a, sign(a)), None);
+		assert_eq!(check_seal(&mut seen, Pair::from_seed(&[2; 32]).public(), 10, b, sign(b)), None);
+
+		let proof = check_seal(&mut seen, key.public(), 10, b, sign(b)).unwrap();
+		assert_eq!(proof, EquivocationProof { authority: key.public(), slot: 10, first: (a, sign(a)), second: (b, sign(b)) });
+	}
+
+	#[test]
+	fn check_seal_forgets_old_slots() {
+		let key = Pair::from_seed(&[1; 32]);
+		let sign = |hash: Hash| key.sign(hash.as_ref());
+		let (a, b) = (Hash::from([1; 32]), Hash::from([2; 32]));
+		let mut seen = BTreeMap::new();
+
+		check_seal(&mut seen, key.public(), 10, a, sign(a));
+		check_seal(&mut seen, key.public(), 10 + KEEP_SLOTS, a, sign(a));
+		assert_eq!(seen.keys().cloned().collect::<Vec<_>>(), vec![10, 10 + KEEP_SLOTS]);
+
+		check_seal(&mut seen, key.public(), 11 + KEEP_SLOTS, a, sign(a));
+		assert_eq!(seen.keys().cloned().collect::<Vec<_>>(), vec![10 + KEEP_SLOTS, 11 + KEEP_SLOTS]);
+		assert_eq!(check_seal(&mut seen, key.public(), 10, b, sign(b)), None);
+	}
+}
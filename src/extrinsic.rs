@@ -0,0 +1,207 @@
+//! Offline construction of signed extrinsics.
+//!
+//! Lets validators sign governance actions on a machine without network
+//! access. The resulting hex can be submitted later with `author_submitExtrinsic`.
+
+use std::str::FromStr;
+use parity_codec::{Compact, Encode};
+use primitives::{ed25519, sr25519, Pair, blake2_256, crypto::Ss58Codec, hexdisplay::HexDisplay};
+use runtime_primitives::generic::Era;
+use structopt::StructOpt;
+use substrate_poa_runtime::{AccountId, Call, Hash, Nonce, SudoCall, UncheckedExtrinsic, ValidatorSetCall};
+
+/// The `sign-extrinsic` command.
+#[derive(Clone, Debug, StructOpt)]
+pub struct SignExtrinsicCmd {
+	/// Secret URI of the signing controller (sr25519) key, or of the sudo key for sudo calls.
+	#[structopt(long = "suri", value_name = "SURI")]
+	suri: String,
+
+	/// Nonce of the signing account.
+	#[structopt(long = "nonce", value_name = "NONCE")]
+	nonce: Nonce,
+
+	/// Hash of the genesis block of the chain.
+	#[structopt(long = "genesis-hash", value_name = "HASH")]
+	genesis_hash: String,
+
+	/// Make the extrinsic mortal, valid for this many blocks. Requires `--era-block` and `--era-block-hash`.
+	#[structopt(long = "era-period", value_name = "BLOCKS")]
+	era_period: Option<u64>,
+
+	/// Number of the block the mortal era starts from, usually a recent finalized block.
+	#[structopt(long = "era-block", value_name = "NUMBER")]
+	era_block: Option<u64>,
+
+	/// Hash of the block the mortal era starts from.
+	#[structopt(long = "era-block-hash", value_name = "HASH")]
+	era_block_hash: Option<String>,
+
+	/// The call to sign.
+	#[structopt(subcommand)]
+	call: ValidatorSetAction,
+}
+
+/// Calls of the `ValidatorSet` module that can be signed offline.
+#[derive(Clone, Debug, StructOpt)]
+pub enum ValidatorSetAction {
+	/// Propose a new validator.
+	#[structopt(name = "propose-validator")]
+	ProposeValidator(ValidatorKeys),
+	/// Add a proposed validator once all validators voted for it.
+	#[structopt(name = "resolve-add-validator")]
+	ResolveAddValidator(ValidatorKeys),
+	/// Propose the removal of a validator.
+	#[structopt(name = "propose-validator-removal")]
+	ProposeValidatorRemoval(ValidatorKeys),
+	/// Remove a validator once all validators voted for it.
+	#[structopt(name = "resolve-remove-validator")]
+	ResolveRemoveValidator(ValidatorKeys),
+	/// Add a validator through sudo, signed with the sudo key.
+	#[structopt(name = "add-validator")]
+	AddValidator(ValidatorKeys),
+	/// Remove a validator through sudo, signed with the sudo key.
+	#[structopt(name = "remove-validator")]
+	RemoveValidator(ValidatorKeys),
+}
+
+/// Keys of the validator a call is about.
+#[derive(Clone, Debug, StructOpt)]
+pub struct ValidatorKeys {
+	/// SS58 address of the controller (sr25519) key.
+	#[structopt(value_name = "ACCOUNT")]
+	account: String,
+	/// SS58 address of the session (ed25519) key.
+	#[structopt(value_name = "SESSION_KEY")]
+	session_key: String,
+}
+
+impl ValidatorKeys {
+	fn parse(&self) -> Result<(AccountId, ed25519::Public), String> {
+		let account = AccountId::from_ss58check(&self.account)
+			.map_err(|e| format!("Invalid account address {}: {:?}", self.account, e))?;
+		let session_key = ed25519::Public::from_ss58check(&self.session_key)
+			.map_err(|e| format!("Invalid session key {}: {:?}", self.session_key, e))?;
+		Ok((account, session_key))
+	}
+}
+
+impl ValidatorSetAction {
+	fn call(&self) -> Result<Call, String> {
+		Ok(match self {
+			ValidatorSetAction::ProposeValidator(keys) => {
+				let (account, key) = keys.parse()?;
+				Call::ValidatorSet(ValidatorSetCall::propose_validator(account, key))
+			},
+			ValidatorSetAction::ResolveAddValidator(keys) => {
+				let (account, key) = keys.parse()?;
+				Call::ValidatorSet(ValidatorSetCall::resolve_add_validator(account, key))
+			},
+			ValidatorSetAction::ProposeValidatorRemoval(keys) => {
+				let (account, key) = keys.parse()?;
+				Call::ValidatorSet(ValidatorSetCall::propose_validator_removal(account, key))
+			},
+			ValidatorSetAction::ResolveRemoveValidator(keys) => {
+				let (account, key) = keys.parse()?;
+				Call::ValidatorSet(ValidatorSetCall::resolve_remove_validator(account, key))
+			},
+			ValidatorSetAction::AddValidator(keys) => {
+				let (account, key) = keys.parse()?;
+				let call = Call::ValidatorSet(ValidatorSetCall::add_validator(account, key));
+				Call::Sudo(SudoCall::sudo(Box::new(call)))
+			},
+			ValidatorSetAction::RemoveValidator(keys) => {
+				let (account, key) = keys.parse()?;
+				let call = Call::ValidatorSet(ValidatorSetCall::remove_validator(account, key));
+				Call::Sudo(SudoCall::sudo(Box::new(call)))
+			},
+		})
+	}
+}
+
+impl SignExtrinsicCmd {
+	/// Print the signed extrinsic as hex.
+	pub fn run(self) -> Result<(), String> {
+		let pair = sr25519::Pair::from_string(&self.suri, None)
+			.map_err(|e| format!("Invalid secret URI: {:?}", e))?;
+		let genesis_hash = parse_hash(&self.genesis_hash)?;
+
+		// Immortal extrinsics are checked against the genesis block, mortal ones
+		// against the block their era starts from.
+		let (era, checkpoint) = match (self.era_period, self.era_block, &self.era_block_hash) {
+			(None, None, None) => (Era::Immortal, genesis_hash),
+			(Some(period), Some(block), Some(hash)) => (Era::mortal(period, block), parse_hash(hash)?),
+			_ => return Err("--era-period, --era-block and --era-block-hash must be given together".into()),
+		};
+
+		let extrinsic = sign(&pair, self.nonce, self.call.call()?, era, checkpoint);
+		println!("0x{}", HexDisplay::from(&extrinsic.encode()));
+		Ok(())
+	}
+}
+
+// Signs `call` the way the runtime checks it: payloads over 256 bytes are signed by their blake2 hash.
+fn sign(pair: &sr25519::Pair, nonce: Nonce, call: Call, era: Era, checkpoint: Hash) -> UncheckedExtrinsic {
+	let payload = (Compact(nonce), call.clone(), era, checkpoint);
+	let signature = payload.using_encoded(|payload| if payload.len() > 256 {
+		pair.sign(&blake2_256(payload)[..])
+	} else {
+		pair.sign(payload)
+	});
+
+	UncheckedExtrinsic::new_signed(nonce, call, pair.public().into(), signature, era)
+}
+
+fn parse_hash(s: &str) -> Result<Hash, String> {
+	let hex = if s.starts_with("0x") { &s[2..] } else { s };
+	Hash::from_str(hex).map_err(|e| format!("Invalid block hash {}: {:?}", s, e))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_codec::Decode;
+	use runtime_primitives::traits::Verify;
+	use substrate_poa_runtime::ConsensusCall;
+
+	// Decodes the extrinsic and checks its signature the way the runtime does.
+	fn check(encoded: &[u8], signer: &AccountId, checkpoint: Hash) -> (Call, bool) {
+		let extrinsic = UncheckedExtrinsic::decode(&mut &encoded[..]).unwrap();
+		let (_, signature, nonce, era) = extrinsic.signature.unwrap();
+
+		let payload = (nonce, extrinsic.function.clone(), era, checkpoint).encode();
+		let valid = if payload.len() > 256 {
+			signature.verify(&blake2_256(&payload)[..], signer)
+		} else {
+			signature.verify(&payload[..], signer)
+		};
+		(extrinsic.function, valid)
+	}
+
+	fn round_trip(call: Call, payload_len: impl Fn(usize) -> bool) {
+		let pair = sr25519::Pair::from_string("//Alice", None).unwrap();
+		let genesis = Hash::from([7; 32]);
+		let payload = (Compact(3 as Nonce), call.clone(), Era::Immortal, genesis).encode();
+		assert!(payload_len(payload.len()));
+
+		let encoded = sign(&pair, 3, call.clone(), Era::Immortal, genesis).encode();
+		assert_eq!(check(&encoded, &pair.public(), genesis), (call.clone(), true));
+		// Signed for another chain.
+		assert_eq!(check(&encoded, &pair.public(), Hash::from([8; 32])), (call, false));
+	}
+
+	#[test]
+	fn short_payloads_are_signed_as_is() {
+		let call = Call::ValidatorSet(ValidatorSetCall::propose_validator(
+			sr25519::Pair::from_string("//Bob", None).unwrap().public(),
+			ed25519::Pair::from_string("//Bob", None).unwrap().public(),
+		));
+		round_trip(call, |len| len <= 256);
+	}
+
+	#[test]
+	fn long_payloads_are_signed_by_their_hash() {
+		let call = Call::Consensus(ConsensusCall::set_code(vec![1; 300]));
+		round_trip(call, |len| len > 256);
+	}
+}
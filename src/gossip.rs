@@ -0,0 +1,270 @@
+//! Signed announcements gossiped between authorities.
+//!
+//! Authorities use them to share off-chain liveness signals, such as heartbeats,
+//! planned maintenance windows and upcoming session key rotations, without
+//! spending block space. Announcements are signed with the session key and only
+//! accepted from members of the current authority set.
+
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use futures::{Future, Stream};
+use log::{debug, warn};
+use parity_codec::{Encode, Decode};
+use parking_lot::RwLock;
+use serde_derive::Serialize;
+use network::PeerId;
+use primitives::{ed25519::{self, Pair}, Pair as PairT, Blake2Hasher};
+use substrate_client::{self as client, Client};
+use substrate_poa_runtime::{opaque::Block, RuntimeApi};
+use tokio::timer::Interval;
+
+/// Interval between two heartbeats of an authority.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Announcements from further in the future than this are rejected.
+const MAX_CLOCK_DRIFT_MS: u64 = 60_000;
+
+/// Announcements older than this are rejected, so that peers cannot replay
+/// them, e.g. to a node that lost its latest announcements on restart.
+const MAX_AGE_MS: u64 = 60 * 60 * 1000;
+
+/// Content of an announcement.
+#[derive(Encode, Decode, Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Announcement {
+	/// The authority is alive and at the given best block.
+	Heartbeat { best_number: u64 },
+	/// The authority will be offline between the two unix timestamps (in seconds).
+	Maintenance { start: u64, end: u64, note: String },
+	/// The authority is about to switch to a new session key.
+	KeyRotation { new_key: ed25519::Public },
+}
+
+impl Announcement {
+	// Only the latest announcement of each kind is kept per authority.
+	fn kind(&self) -> u8 {
+		match self {
+			Announcement::Heartbeat { .. } => 0,
+			Announcement::Maintenance { .. } => 1,
+			Announcement::KeyRotation { .. } => 2,
+		}
+	}
+}
+
+/// An announcement signed by an authority.
+#[derive(Encode, Decode, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedAnnouncement {
+	/// Content of the announcement.
+	pub announcement: Announcement,
+	/// Creation time in milliseconds since the unix epoch.
+	pub timestamp: u64,
+	/// Session key of the authority.
+	pub authority: ed25519::Public,
+	/// Signature of `(announcement, timestamp)` by `authority`.
+	#[serde(skip)]
+	pub signature: ed25519::Signature,
+}
+
+impl SignedAnnouncement {
+	fn payload(announcement: &Announcement, timestamp: u64) -> Vec<u8> {
+		(announcement, timestamp).encode()
+	}
+
+	fn verify(&self) -> bool {
+		let payload = Self::payload(&self.announcement, self.timestamp);
+		Pair::verify(&self.signature, &payload[..], &self.authority)
+	}
+}
+
+/// Outcome of importing a received announcement.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Import {
+	/// Accepted, or ignored as outdated, too old or too far in the future.
+	Accepted,
+	/// The message could not be decoded.
+	Malformed,
+	/// Validly signed, but not by a current authority. This is expected around
+	/// authority set changes, when peers disagree on the set for a while.
+	UnknownAuthority,
+	/// The signature does not match the announced authority.
+	BadSignature,
+}
+
+#[derive(Default)]
+struct Inner {
+	local_key: Option<Arc<Pair>>,
+	authorities: HashSet<ed25519::Public>,
+	latest: HashMap<(ed25519::Public, u8), SignedAnnouncement>,
+	// Encoded announcements to send, and the peer they came from.
+	outbox: Vec<(Vec<u8>, Option<PeerId>)>,
+}
+
+/// Shared state of the announcement gossip.
+#[derive(Clone, Default)]
+pub struct Gossip {
+	inner: Arc<RwLock<Inner>>,
+}
+
+impl Gossip {
+	/// Set the key used to sign local announcements.
+	pub fn set_local_key(&self, key: Arc<Pair>) {
+		self.inner.write().local_key = Some(key);
+	}
+
+	/// Stop signing local announcements, e.g. when the local key left the authority set.
+	pub fn clear_local_key(&self) {
+		self.inner.write().local_key = None;
+	}
+
+	/// Set the current authority set. Announcements of other keys are dropped.
+	pub fn set_authorities(&self, authorities: Vec<ed25519::Public>) {
+		let mut inner = self.inner.write();
+		inner.authorities = authorities.into_iter().collect();
+
+		let authorities = inner.authorities.clone();
+		inner.latest.retain(|(authority, _), _| authorities.contains(authority));
+	}
+
+	/// Sign an announcement with the local key and queue it for broadcast.
+	pub fn announce(&self, announcement: Announcement) -> Result<SignedAnnouncement, &'static str> {
+		let mut inner = self.inner.write();
+		let key = inner.local_key.clone().ok_or("Node is not running with an authority key")?;
+
+		let timestamp = now_ms();
+		let signature = key.sign(&SignedAnnouncement::payload(&announcement, timestamp)[..]);
+		let signed = SignedAnnouncement { announcement, timestamp, authority: key.public(), signature };
+
+		inner.outbox.push((signed.encode(), None));
+		inner.latest.insert((signed.authority.clone(), signed.announcement.kind()), signed.clone());
+		Ok(signed)
+	}
+
+	/// Import an encoded announcement received from `who`.
+	pub fn import(&self, who: &PeerId, data: &[u8]) -> Import {
+		let signed = match SignedAnnouncement::decode(&mut &data[..]) {
+			Some(signed) => signed,
+			None => return Import::Malformed,
+		};
+		if !signed.verify() {
+			return Import::BadSignature;
+		}
+
+		let mut inner = self.inner.write();
+		if !inner.authorities.contains(&signed.authority) {
+			return Import::UnknownAuthority;
+		}
+
+		let now = now_ms();
+		if signed.timestamp > now + MAX_CLOCK_DRIFT_MS {
+			debug!(target: "poa", "Ignoring announcement from the future by {}", signed.authority);
+			return Import::Accepted;
+		}
+		if signed.timestamp + MAX_AGE_MS < now {
+			debug!(target: "poa", "Ignoring stale announcement by {}", signed.authority);
+			return Import::Accepted;
+		}
+
+		let key = (signed.authority.clone(), signed.announcement.kind());
+		let is_newer = inner.latest.get(&key).map_or(true, |latest| latest.timestamp < signed.timestamp);
+		if is_newer {
+			// Relay to everyone but the sender.
+			inner.outbox.push((data.to_vec(), Some(who.clone())));
+			inner.latest.insert(key, signed);
+		}
+
+		Import::Accepted
+	}
+
+	/// Encoded latest announcements, e.g. for a newly connected peer.
+	pub fn encoded_announcements(&self) -> Vec<Vec<u8>> {
+		self.inner.read().latest.values().map(Encode::encode).collect()
+	}
+
+	/// Take the queued messages and the peer each one must not be sent to.
+	pub fn drain_outbox(&self) -> Vec<(Vec<u8>, Option<PeerId>)> {
+		std::mem::replace(&mut self.inner.write().outbox, Vec::new())
+	}
+
+	/// Latest announcements of all authorities.
+	pub fn announcements(&self) -> Vec<SignedAnnouncement> {
+		let mut announcements: Vec<_> = self.inner.read().latest.values().cloned().collect();
+		announcements.sort_by_key(|a| a.timestamp);
+		announcements
+	}
+}
+
+/// Announce a heartbeat with the best block every `HEARTBEAT_INTERVAL`.
+pub fn heartbeats<B, E>(
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	gossip: Gossip,
+) -> impl Future<Item=(), Error=()> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	Interval::new(Instant::now(), HEARTBEAT_INTERVAL)
+		.map_err(|e| warn!(target: "poa", "Heartbeat timer failed: {:?}", e))
+		.for_each(move |_| {
+			if let Ok(info) = client.info() {
+				let _ = gossip.announce(Announcement::Heartbeat { best_number: info.chain.best_number });
+			}
+			Ok(())
+		})
+}
+
+fn now_ms() -> u64 {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn signed_by(key: &Pair) -> SignedAnnouncement {
+		signed_at(key, now_ms())
+	}
+
+	fn signed_at(key: &Pair, timestamp: u64) -> SignedAnnouncement {
+		let announcement = Announcement::Heartbeat { best_number: 1 };
+		let signature = key.sign(&SignedAnnouncement::payload(&announcement, timestamp)[..]);
+		SignedAnnouncement { announcement, timestamp, authority: key.public(), signature }
+	}
+
+	#[test]
+	fn import_tells_unknown_authorities_from_bad_signatures() {
+		let authority = Pair::from_seed(&[1; 32]);
+		let other = Pair::from_seed(&[2; 32]);
+		let gossip = Gossip::default();
+		gossip.set_authorities(vec![authority.public()]);
+		let peer = PeerId::random();
+
+		assert_eq!(gossip.import(&peer, &signed_by(&authority).encode()), Import::Accepted);
+		assert_eq!(gossip.import(&peer, &signed_by(&other).encode()), Import::UnknownAuthority);
+		assert_eq!(gossip.import(&peer, &[1, 2, 3]), Import::Malformed);
+
+		let mut forged = signed_by(&other);
+		forged.authority = authority.public();
+		assert_eq!(gossip.import(&peer, &forged.encode()), Import::BadSignature);
+
+		assert_eq!(gossip.announcements().len(), 1);
+	}
+
+	#[test]
+	fn stale_announcements_are_ignored() {
+		let authority = Pair::from_seed(&[1; 32]);
+		let gossip = Gossip::default();
+		gossip.set_authorities(vec![authority.public()]);
+		let peer = PeerId::random();
+
+		let stale = signed_at(&authority, now_ms() - MAX_AGE_MS - 1000);
+		assert_eq!(gossip.import(&peer, &stale.encode()), Import::Accepted);
+		assert!(gossip.announcements().is_empty());
+		assert!(gossip.drain_outbox().is_empty());
+
+		let recent = signed_at(&authority, now_ms() - MAX_AGE_MS + 60_000);
+		assert_eq!(gossip.import(&peer, &recent.encode()), Import::Accepted);
+		let announcements = gossip.announcements();
+		assert_eq!(announcements.len(), 1);
+		assert_eq!(announcements[0].timestamp, recent.timestamp);
+	}
+}
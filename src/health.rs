@@ -0,0 +1,140 @@
+//! Liveness and readiness probes of the node, served over HTTP.
+//!
+//! `/health` only fails while the client does not answer. `/ready` also fails
+//! while the node has too few peers, is syncing or, if required, while none of
+//! its keys is an authority.
+
+use std::{net::SocketAddr, sync::Arc};
+use futures::{Future, Stream};
+use hyper::StatusCode;
+use parking_lot::RwLock;
+use serde_derive::Serialize;
+use primitives::{ed25519, Blake2Hasher};
+use runtime_primitives::{generic::BlockId, traits::ProvideRuntimeApi};
+use substrate_client::{self as client, Client};
+use substrate_poa_runtime::{opaque::Block, BlockNumber, RuntimeApi};
+use consensus_authorities::AuthoritiesApi;
+use network::SyncProvider;
+use crate::authoring::LocalKeys;
+use crate::http;
+
+/// Limits beyond which the node is reported unhealthy.
+#[derive(Clone, Debug)]
+pub struct Thresholds {
+	/// Number of blocks the node may be behind the best block of its peers and still be ready.
+	pub max_blocks_behind: BlockNumber,
+	/// Number of peers below which the node is not ready.
+	pub min_peers: usize,
+	/// Whether the node is only ready when one of its keys is a current authority.
+	pub require_authority: bool,
+}
+
+impl Default for Thresholds {
+	fn default() -> Self {
+		Thresholds {
+			max_blocks_behind: 5,
+			min_peers: 1,
+			require_authority: false,
+		}
+	}
+}
+
+/// State of the node as reported by the probes.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+	/// Whether the best block is within `max_blocks_behind` of the target block.
+	pub is_synced: bool,
+	/// Number of connected peers.
+	pub peers: usize,
+	/// Number of the best block.
+	pub best_block: BlockNumber,
+	/// Number of the best block seen from peers, or of the best block if none was seen.
+	pub target_block: BlockNumber,
+	/// Whether one of the local keys is a current authority, `None` without local keys.
+	pub is_authority: Option<bool>,
+}
+
+/// Network state of the node, as last reported by the network service.
+#[derive(Clone, Default)]
+pub struct NetworkStatus {
+	inner: Arc<RwLock<(usize, Option<BlockNumber>)>>,
+}
+
+/// Keep `status` up to date with the sync status of `network`.
+pub fn track_network<N>(network: Arc<N>, status: NetworkStatus) -> impl Future<Item=(), Error=()> where
+	N: SyncProvider<Block>,
+{
+	network.status().for_each(move |sync_status| {
+		*status.inner.write() = (sync_status.num_peers, sync_status.sync.best_seen_block);
+		Ok(())
+	})
+}
+
+/// Serve the probes on `addr` until the returned future is dropped.
+pub fn start_http<B, E>(
+	addr: &SocketAddr,
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	status: NetworkStatus,
+	keys: LocalKeys,
+	thresholds: Thresholds,
+) -> Result<impl Future<Item=(), Error=()>, String> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	http::serve("health", addr, move |path| {
+		let ready = match path {
+			"/health" => false,
+			"/ready" => true,
+			_ => return None,
+		};
+
+		let report = report(&client, &status, &keys.publics(), &thresholds);
+		// The node is alive as long as its client answers, whatever its peers.
+		let healthy = match report {
+			Some(ref report) if ready => is_ready(report, &thresholds),
+			Some(_) => true,
+			None => false,
+		};
+
+		let code = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+		Some((code, "application/json", serde_json::to_string(&report).unwrap_or_default()))
+	})
+}
+
+fn is_ready(report: &Report, thresholds: &Thresholds) -> bool {
+	report.peers >= thresholds.min_peers
+		&& report.is_synced
+		&& (!thresholds.require_authority || report.is_authority == Some(true))
+}
+
+// Current state of the node, `None` if the client is unavailable.
+fn report<B, E>(
+	client: &Client<B, E, Block, RuntimeApi>,
+	status: &NetworkStatus,
+	keys: &[ed25519::Public],
+	thresholds: &Thresholds,
+) -> Option<Report> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let info = client.info().ok()?;
+	let best_block = info.chain.best_number;
+	let (peers, best_seen) = *status.inner.read();
+	let target_block = best_seen.map_or(best_block, |seen| seen.max(best_block));
+
+	let is_authority = if keys.is_empty() {
+		None
+	} else {
+		let authorities = client.runtime_api().authorities(&BlockId::hash(info.chain.best_hash)).ok()?;
+		Some(keys.iter().any(|key| authorities.contains(key)))
+	};
+
+	Some(Report {
+		is_synced: target_block - best_block <= thresholds.max_blocks_behind,
+		peers,
+		best_block,
+		target_block,
+		is_authority,
+	})
+}
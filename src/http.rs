@@ -0,0 +1,52 @@
+//! Plain HTTP servers of the node, shared by the metrics and health endpoints.
+
+use std::{net::SocketAddr, sync::Arc};
+use futures::Future;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header::CONTENT_TYPE, service::service_fn_ok};
+use log::warn;
+
+/// Reply to a GET request: status, content type and body.
+pub type Reply = (StatusCode, &'static str, String);
+
+/// Address of a server on `port`, on all interfaces if `external` and on localhost otherwise.
+pub fn listen_addr(port: u16, external: bool) -> SocketAddr {
+	let ip = if external { [0, 0, 0, 0] } else { [127, 0, 0, 1] };
+	SocketAddr::from((ip, port))
+}
+
+/// Serve GET requests on `addr` until the returned future is dropped.
+///
+/// `handle` answers the requested path, `None` meaning not found.
+pub fn serve<F>(name: &'static str, addr: &SocketAddr, handle: F) -> Result<impl Future<Item=(), Error=()>, String> where
+	F: Fn(&str) -> Option<Reply> + Send + Sync + 'static,
+{
+	let builder = Server::try_bind(addr).map_err(|e| format!("Unable to start {} server on {}: {}", name, addr, e))?;
+	let handle = Arc::new(handle);
+
+	let server = builder.serve(move || {
+		let handle = handle.clone();
+		service_fn_ok(move |request: Request<Body>| {
+			let reply = if *request.method() == Method::GET {
+				handle(request.uri().path())
+			} else {
+				None
+			};
+
+			match reply {
+				Some((status, content_type, body)) => {
+					let mut response = Response::new(Body::from(body));
+					*response.status_mut() = status;
+					response.headers_mut().insert(CONTENT_TYPE, content_type.parse().expect("valid header value; qed"));
+					response
+				},
+				None => {
+					let mut response = Response::new(Body::empty());
+					*response.status_mut() = StatusCode::NOT_FOUND;
+					response
+				},
+			}
+		})
+	});
+
+	Ok(server.map_err(move |e| warn!(target: "poa", "The {} server failed: {}", name, e)))
+}
@@ -0,0 +1,160 @@
+//! Session and controller key management.
+
+use std::{fs, io::Write, path::{Path, PathBuf}};
+use primitives::{ed25519, sr25519, Pair, crypto::Ss58Codec, hexdisplay::HexDisplay};
+use structopt::StructOpt;
+use crate::cli;
+
+/// Signature scheme of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+	/// Session keys, used for Aura and GRANDPA.
+	Ed25519,
+	/// Controller keys, used to sign transactions.
+	Sr25519,
+}
+
+impl std::str::FromStr for Scheme {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"ed25519" => Ok(Scheme::Ed25519),
+			"sr25519" => Ok(Scheme::Sr25519),
+			other => Err(format!("Unknown scheme {}, expected `ed25519` or `sr25519`", other)),
+		}
+	}
+}
+
+/// The `key` command.
+#[derive(Clone, Debug, StructOpt)]
+pub enum KeyCmd {
+	/// Generate a random key and print its secret phrase.
+	#[structopt(name = "generate")]
+	Generate {
+		/// `ed25519` for session keys, `sr25519` for controller keys.
+		#[structopt(long = "scheme", default_value = "ed25519", raw(possible_values = "&[\"ed25519\", \"sr25519\"]"))]
+		scheme: Scheme,
+	},
+
+	/// Print the public key and address of a secret URI, e.g. `//Alice` or a secret phrase.
+	#[structopt(name = "inspect")]
+	Inspect {
+		/// Secret URI of the key.
+		#[structopt(value_name = "SURI")]
+		suri: String,
+
+		/// `ed25519` for session keys, `sr25519` for controller keys.
+		#[structopt(long = "scheme", default_value = "ed25519", raw(possible_values = "&[\"ed25519\", \"sr25519\"]"))]
+		scheme: Scheme,
+	},
+
+	/// Add a session key, given by its secret URI, e.g. `//Alice` or a secret phrase, to the keystore of the node.
+	///
+	/// A running node authors with the key once it joins the authority set. GRANDPA keeps voting
+	/// with the key the node was started with, so restart the node to vote with the new key.
+	#[structopt(name = "insert")]
+	Insert {
+		/// Secret URI of the session key.
+		#[structopt(value_name = "SURI")]
+		suri: String,
+
+		/// Base path of the node, as given to `--base-path`.
+		#[structopt(long = "base-path", value_name = "PATH")]
+		base_path: String,
+
+		/// Chain of the node, as given to `--chain`.
+		#[structopt(long = "chain", value_name = "CHAIN_SPEC", default_value = "local")]
+		chain: String,
+
+		/// Password of the keystore, as given to `--password`.
+		#[structopt(long = "password", default_value = "")]
+		password: String,
+	},
+}
+
+impl KeyCmd {
+	/// Run the command.
+	pub fn run(self) -> Result<(), String> {
+		match self {
+			KeyCmd::Generate { scheme: Scheme::Ed25519 } => generate::<ed25519::Pair>(),
+			KeyCmd::Generate { scheme: Scheme::Sr25519 } => generate::<sr25519::Pair>(),
+			KeyCmd::Inspect { suri, scheme: Scheme::Ed25519 } => inspect::<ed25519::Pair>(&suri),
+			KeyCmd::Inspect { suri, scheme: Scheme::Sr25519 } => inspect::<sr25519::Pair>(&suri),
+			KeyCmd::Insert { suri, base_path, chain, password } => insert(&suri, &base_path, &chain, &password),
+		}
+	}
+}
+
+fn generate<P: Pair>() -> Result<(), String> where P::Public: Ss58Codec + AsRef<[u8]> {
+	let (pair, phrase) = P::generate_with_phrase(None);
+	println!("Secret phrase: {}", phrase);
+	print_public(&pair.public());
+	Ok(())
+}
+
+fn inspect<P: Pair>(suri: &str) -> Result<(), String> where P::Public: Ss58Codec + AsRef<[u8]> {
+	let pair = P::from_string(suri, None).map_err(|e| format!("Invalid secret URI: {:?}", e))?;
+	print_public(&pair.public());
+	Ok(())
+}
+
+fn print_public<T: Ss58Codec + AsRef<[u8]>>(public: &T) {
+	println!("Public key (hex): 0x{}", HexDisplay::from(&public.as_ref()));
+	println!("Address (SS58): {}", public.to_ss58check());
+}
+
+// The keystore stores each key as a JSON string in a file named after the hex public key.
+// Keys generated by the node are stored as phrases, inserted keys as secret URIs.
+fn insert(suri: &str, base_path: &str, chain: &str, password: &str) -> Result<(), String> {
+	let pair = ed25519::Pair::from_string(suri, Some(password))
+		.map_err(|e| format!("Invalid secret URI: {:?}", e))?;
+	let public = pair.public();
+
+	let spec = cli::load_spec(chain)?.ok_or_else(|| format!("Unknown chain {}", chain))?;
+	let mut path: PathBuf = [base_path, "chains", spec.id(), "keystore"].iter().collect();
+	fs::create_dir_all(&path).map_err(|e| format!("Unable to create keystore {}: {}", path.display(), e))?;
+
+	let others = fs::read_dir(&path)
+		.map_err(|e| format!("Unable to read keystore {}: {}", path.display(), e))?
+		.count();
+
+	path.push(format!("{}", HexDisplay::from(&public.as_ref())));
+	let mut file = fs::File::create(&path).map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+	file.write_all(serde_json::to_string(suri).map_err(|e| e.to_string())?.as_bytes())
+		.and_then(|_| file.flush())
+		.map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+
+	println!("Inserted session key {} into {}", public.to_ss58check(), path.display());
+	if others > 0 {
+		eprintln!("The keystore holds other keys as well. The node authors with whichever is a current authority.");
+	}
+	Ok(())
+}
+
+/// Read the session key of a keystore file, written by the node or by `key insert`.
+///
+/// A secret phrase is a secret URI as well, so both kinds of files are read the same way.
+pub fn read_session_key(path: &Path, password: &str) -> Result<ed25519::Pair, String> {
+	let file = fs::File::open(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+	let suri: String = serde_json::from_reader(file)
+		.map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+	ed25519::Pair::from_string(&suri, Some(password))
+		.map_err(|e| format!("Invalid secret URI in {}: {:?}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn session_keys_are_read_as_secret_uris() {
+		let path = std::env::temp_dir().join(format!("substrate-poa-key-{}", std::process::id()));
+		fs::write(&path, serde_json::to_string("//Alice").unwrap()).unwrap();
+
+		let pair = read_session_key(&path, "").unwrap();
+		fs::remove_file(&path).unwrap();
+
+		assert_eq!(pair.public(), ed25519::Pair::from_string("//Alice", None).unwrap().public());
+	}
+}
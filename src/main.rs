@@ -6,6 +6,18 @@
 mod chain_spec;
 mod service;
 mod cli;
+mod rpc;
+mod protocol;
+mod gossip;
+mod seal;
+mod key;
+mod extrinsic;
+mod authoring;
+mod equivocation;
+mod http;
+mod metrics;
+mod health;
+mod reload;
 
 pub use substrate_cli::{VersionInfo, IntoExit, error};
 
@@ -0,0 +1,297 @@
+//! Prometheus metrics of the node, served over HTTP on `/metrics`.
+
+use std::{cmp, fmt::Write, net::SocketAddr, sync::Arc, time::{Instant, SystemTime, UNIX_EPOCH}};
+use futures::{Future, Stream};
+use hyper::StatusCode;
+use parking_lot::RwLock;
+use primitives::{ed25519, Blake2Hasher};
+use runtime_primitives::{generic::BlockId, traits::{Block as BlockT, Header as HeaderT, AuthorityIdFor, ProvideRuntimeApi}};
+use substrate_client::{self as client, BlockchainEvents, Client};
+use substrate_poa_runtime::{opaque::Block, RuntimeApi};
+use consensus_authorities::AuthoritiesApi;
+use consensus_common::{BlockImport, ImportBlock, ImportResult};
+use transaction_pool::txpool::{ChainApi, Pool};
+use crate::authoring::LocalKeys;
+use crate::http;
+use crate::equivocation::seal_of;
+
+/// Upper bounds of the block import time buckets, in seconds.
+const IMPORT_BUCKETS: [f64; 8] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Default)]
+struct Counters {
+	peers: usize,
+	slots_authored: u64,
+	slots_missed: u64,
+	import_buckets: [u64; 8],
+	import_count: u64,
+	import_sum: f64,
+}
+
+/// Metrics collected by the node tasks, next to the ones read from the client.
+#[derive(Clone, Default)]
+pub struct Metrics {
+	counters: Arc<RwLock<Counters>>,
+}
+
+impl Metrics {
+	/// Set the number of connected peers.
+	pub fn set_peers(&self, peers: usize) {
+		self.counters.write().peers = peers;
+	}
+
+	fn note_slots(&self, authored: u64, missed: u64) {
+		let mut counters = self.counters.write();
+		counters.slots_authored += authored;
+		counters.slots_missed += missed;
+	}
+
+	fn note_import(&self, secs: f64) {
+		let mut counters = self.counters.write();
+		for (bucket, bound) in counters.import_buckets.iter_mut().zip(IMPORT_BUCKETS.iter()) {
+			if secs <= *bound {
+				*bucket += 1;
+			}
+		}
+		counters.import_count += 1;
+		counters.import_sum += secs;
+	}
+}
+
+/// Block import that records the time taken by the wrapped import.
+pub struct TimedImport<I> {
+	inner: Arc<I>,
+	metrics: Metrics,
+}
+
+impl<I> TimedImport<I> {
+	/// Time the imports of `inner`.
+	pub fn new(inner: Arc<I>, metrics: Metrics) -> Self {
+		TimedImport { inner, metrics }
+	}
+}
+
+impl<I: BlockImport<Block>> BlockImport<Block> for TimedImport<I> {
+	type Error = I::Error;
+
+	fn check_block(
+		&self,
+		hash: <Block as BlockT>::Hash,
+		parent_hash: <Block as BlockT>::Hash,
+	) -> Result<ImportResult, Self::Error> {
+		self.inner.check_block(hash, parent_hash)
+	}
+
+	fn import_block(
+		&self,
+		block: ImportBlock<Block>,
+		new_authorities: Option<Vec<AuthorityIdFor<Block>>>,
+	) -> Result<ImportResult, Self::Error> {
+		let start = Instant::now();
+		let result = self.inner.import_block(block, new_authorities);
+		let elapsed = start.elapsed();
+		self.metrics.note_import(elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9);
+		result
+	}
+}
+
+/// Count the slots of `keys` that got a finalized block, and the ones that did not.
+///
+/// Only slots after the start of the node are counted. Finalized blocks are counted
+/// once each, so forks and reorgs of the best chain do not count twice.
+pub fn track_slots<B, E>(
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	keys: LocalKeys,
+	metrics: Metrics,
+	slot_duration: u64,
+) -> impl Future<Item=(), Error=()> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let first_slot = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / slot_duration;
+	let mut last_finalized = client.info().map(|info| info.chain.finalized_number).unwrap_or_default();
+
+	client.finality_notification_stream().for_each(move |notification| {
+		// Notifications are not sent for every finalized block, so the blocks since
+		// the last notification are walked back to from the new one.
+		let finalized = *notification.header.number();
+		let mut header = Some(notification.header);
+		while let Some(current) = header.take() {
+			if *current.number() <= last_finalized {
+				break;
+			}
+			if let Some((authored, missed)) = slots_of(&client, &current, &keys.publics(), first_slot) {
+				metrics.note_slots(authored, missed);
+			}
+			header = client.header(&BlockId::hash(*current.parent_hash())).ok().and_then(|parent| parent);
+		}
+		last_finalized = cmp::max(last_finalized, finalized);
+		Ok(())
+	})
+}
+
+// Local slots filled by the block and local slots skipped since its parent.
+fn slots_of<B, E>(
+	client: &Client<B, E, Block, RuntimeApi>,
+	header: &<Block as BlockT>::Header,
+	keys: &[ed25519::Public],
+	first_slot: u64,
+) -> Option<(u64, u64)> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let (slot, _, _) = seal_of(header)?;
+	if slot < first_slot {
+		return None;
+	}
+
+	let parent = BlockId::hash(*header.parent_hash());
+	let authorities = client.runtime_api().authorities(&parent).ok()?;
+	if authorities.is_empty() {
+		return None;
+	}
+	let is_local = |slot: u64| keys.contains(&authorities[(slot % authorities.len() as u64) as usize]);
+
+	// The genesis block has no seal.
+	let parent_slot = client.header(&parent).ok()?.and_then(|parent| seal_of(&parent)).map_or(0, |(slot, _, _)| slot);
+	let missed = (cmp::max(parent_slot + 1, first_slot)..slot).filter(|slot| is_local(*slot)).count();
+
+	Some((is_local(slot) as u64, missed as u64))
+}
+
+/// Serve the metrics on `addr` until the returned future is dropped.
+pub fn start_http<B, E, A>(
+	addr: &SocketAddr,
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	pool: Arc<Pool<A>>,
+	metrics: Metrics,
+) -> Result<impl Future<Item=(), Error=()>, String> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+	A: ChainApi<Block=Block> + 'static,
+{
+	http::serve("metrics", addr, move |path| match path {
+		"/metrics" => Some((StatusCode::OK, "text/plain; version=0.0.4", render(&client, &pool, &metrics))),
+		_ => None,
+	})
+}
+
+// Values read from the client and the pool when the metrics are scraped.
+#[derive(Default)]
+struct ChainStatus {
+	best_number: Option<u64>,
+	finalized_number: Option<u64>,
+	validators: Option<usize>,
+	pool_ready: usize,
+	pool_future: usize,
+}
+
+// Metrics in the Prometheus text format.
+fn render<B, E, A>(
+	client: &Client<B, E, Block, RuntimeApi>,
+	pool: &Pool<A>,
+	metrics: &Metrics,
+) -> String where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+	A: ChainApi<Block=Block>,
+{
+	let pool_status = pool.status();
+	let mut status = ChainStatus {
+		pool_ready: pool_status.ready,
+		pool_future: pool_status.future,
+		..Default::default()
+	};
+
+	if let Ok(info) = client.info() {
+		status.best_number = Some(info.chain.best_number);
+		status.finalized_number = Some(info.chain.finalized_number);
+		status.validators = client.runtime_api().authorities(&BlockId::hash(info.chain.best_hash)).ok()
+			.map(|authorities| authorities.len());
+	}
+
+	to_text(&status, &metrics.counters.read())
+}
+
+// Status and counters in the Prometheus text format.
+fn to_text(status: &ChainStatus, counters: &Counters) -> String {
+	let mut out = String::new();
+	let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+		let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+	};
+
+	if let Some(number) = status.best_number {
+		metric("poa_block_height", "gauge", "Number of the best block.", number.to_string());
+	}
+	if let Some(number) = status.finalized_number {
+		metric("poa_finalized_height", "gauge", "Number of the last finalized block.", number.to_string());
+	}
+	if let Some(validators) = status.validators {
+		metric("poa_validators", "gauge", "Size of the validator set at the best block.", validators.to_string());
+	}
+
+	metric("poa_transaction_pool_ready", "gauge", "Transactions ready to be included.", status.pool_ready.to_string());
+	metric("poa_transaction_pool_future", "gauge", "Transactions waiting for earlier ones.", status.pool_future.to_string());
+
+	metric("poa_peers", "gauge", "Connected authorized peers.", counters.peers.to_string());
+	metric("poa_slots_authored_total", "counter", "Slots of a local key with a finalized block.", counters.slots_authored.to_string());
+	metric("poa_slots_missed_total", "counter", "Slots of a local key without a finalized block.", counters.slots_missed.to_string());
+
+	let _ = writeln!(out, "# HELP poa_block_import_seconds Time taken to import blocks received from the network.");
+	let _ = writeln!(out, "# TYPE poa_block_import_seconds histogram");
+	for (bound, count) in IMPORT_BUCKETS.iter().zip(counters.import_buckets.iter()) {
+		let _ = writeln!(out, "poa_block_import_seconds_bucket{{le=\"{}\"}} {}", bound, count);
+	}
+	let _ = writeln!(out, "poa_block_import_seconds_bucket{{le=\"+Inf\"}} {}", counters.import_count);
+	let _ = writeln!(out, "poa_block_import_seconds_sum {}", counters.import_sum);
+	let _ = writeln!(out, "poa_block_import_seconds_count {}", counters.import_count);
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn render_follows_the_text_format() {
+		let status = ChainStatus { best_number: Some(12), finalized_number: Some(10), validators: None, pool_ready: 2, pool_future: 0 };
+		let metrics = Metrics::default();
+		metrics.set_peers(3);
+		metrics.note_slots(1, 2);
+
+		let out = to_text(&status, &metrics.counters.read());
+		assert!(out.contains("# HELP poa_block_height Number of the best block.\n# TYPE poa_block_height gauge\npoa_block_height 12\n"));
+		assert!(out.contains("\npoa_finalized_height 10\n"));
+		assert!(!out.contains("poa_validators"));
+		assert!(out.contains("\npoa_transaction_pool_ready 2\n"));
+		assert!(out.contains("\npoa_peers 3\n"));
+		assert!(out.contains("# TYPE poa_slots_authored_total counter\npoa_slots_authored_total 1\n"));
+		assert!(out.contains("\npoa_slots_missed_total 2\n"));
+		assert!(out.ends_with("\n"));
+	}
+
+	#[test]
+	fn import_buckets_are_cumulative() {
+		let metrics = Metrics::default();
+		metrics.note_import(0.02);
+		metrics.note_import(0.3);
+		metrics.note_import(10.0);
+
+		let out = to_text(&ChainStatus::default(), &metrics.counters.read());
+		let buckets: Vec<&str> = out.lines().filter(|line| line.starts_with("poa_block_import_seconds")).collect();
+		assert_eq!(buckets, vec![
+			"poa_block_import_seconds_bucket{le=\"0.01\"} 0",
+			"poa_block_import_seconds_bucket{le=\"0.05\"} 1",
+			"poa_block_import_seconds_bucket{le=\"0.1\"} 1",
+			"poa_block_import_seconds_bucket{le=\"0.25\"} 1",
+			"poa_block_import_seconds_bucket{le=\"0.5\"} 2",
+			"poa_block_import_seconds_bucket{le=\"1\"} 2",
+			"poa_block_import_seconds_bucket{le=\"2.5\"} 2",
+			"poa_block_import_seconds_bucket{le=\"5\"} 2",
+			"poa_block_import_seconds_bucket{le=\"+Inf\"} 3",
+			"poa_block_import_seconds_sum 10.32",
+			"poa_block_import_seconds_count 3",
+		]);
+	}
+}
@@ -0,0 +1,201 @@
+//! Network protocol specialization of the PoA node.
+
+use std::{collections::HashSet, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+use futures::{Future, Stream};
+use log::{debug, info, warn};
+use parking_lot::RwLock;
+use network::{
+	specialization::NetworkSpecialization, message::Message,
+	Context, PeerId, Severity, StatusMessage, SyncProvider, SyncState,
+};
+use primitives::Blake2Hasher;
+use runtime_primitives::{generic::BlockId, traits::ProvideRuntimeApi};
+use substrate_client::{self as client, BlockchainEvents, Client};
+use substrate_poa_runtime::{opaque::Block, NodeAuthApi, RuntimeApi};
+use consensus_authorities::AuthoritiesApi;
+use crate::gossip::{Gossip, Import};
+use crate::metrics::Metrics;
+
+/// Peers that may connect to this node, as registered on chain.
+///
+/// `None` means that any peer is accepted, either because peer authorization
+/// is turned off on chain or because it is disabled locally.
+pub type AuthorizedPeers = Arc<RwLock<Option<HashSet<PeerId>>>>;
+
+/// Protocol attachment for the PoA node.
+///
+/// Drops peers that are not in the on-chain registry of authorized peers
+/// and carries the announcement gossip between authorities.
+pub struct NodeProtocol {
+	authorized_peers: AuthorizedPeers,
+	gossip: Gossip,
+	metrics: Metrics,
+	peers: HashSet<PeerId>,
+}
+
+impl NodeProtocol {
+	/// Create a new `NodeProtocol` enforcing the given registry.
+	pub fn new(authorized_peers: AuthorizedPeers, gossip: Gossip, metrics: Metrics) -> Self {
+		NodeProtocol {
+			authorized_peers,
+			gossip,
+			metrics,
+			peers: HashSet::new(),
+		}
+	}
+
+	fn is_authorized(&self, who: &PeerId) -> bool {
+		self.authorized_peers.read().as_ref().map_or(true, |peers| peers.contains(who))
+	}
+}
+
+impl NetworkSpecialization<Block> for NodeProtocol {
+	fn status(&self) -> Vec<u8> {
+		Vec::new()
+	}
+
+	fn on_connect(&mut self, ctx: &mut Context<Block>, who: PeerId, _status: StatusMessage<Block>) {
+		if !self.is_authorized(&who) {
+			// Not banned, since the registry may be out of date on either side.
+			debug!(target: "poa", "Rejecting unauthorized peer {}", who);
+			ctx.report_peer(who, Severity::Useless("Peer is not authorized on chain".into()));
+			return;
+		}
+
+		// Bring the new peer up to date with the latest announcements.
+		for data in self.gossip.encoded_announcements() {
+			ctx.send_message(who.clone(), Message::ChainSpecific(data));
+		}
+
+		self.peers.insert(who);
+		self.metrics.set_peers(self.peers.len());
+	}
+
+	fn on_disconnect(&mut self, _ctx: &mut Context<Block>, who: PeerId) {
+		self.peers.remove(&who);
+		self.metrics.set_peers(self.peers.len());
+	}
+
+	fn on_message(&mut self, ctx: &mut Context<Block>, who: PeerId, message: &mut Option<Message<Block>>) {
+		match message.take() {
+			Some(Message::ChainSpecific(data)) => match self.gossip.import(&who, &data) {
+				Import::Accepted => {},
+				// Peers may still see an older authority set, or relay heartbeats of nodes
+				// that left it, so these are dropped without blaming the peer.
+				Import::UnknownAuthority => debug!(target: "poa", "Dropping announcement of an unknown authority from {}", who),
+				Import::Malformed => ctx.report_peer(who, Severity::Useless("Malformed announcement".into())),
+				Import::BadSignature => ctx.report_peer(who, Severity::Bad("Invalid announcement signature".into())),
+			},
+			other => *message = other,
+		}
+	}
+
+	// Sends queued announcements and drops connected peers that have been
+	// deauthorized since they connected.
+	fn maintain_peers(&mut self, ctx: &mut Context<Block>) {
+		for (data, from) in self.gossip.drain_outbox() {
+			for who in self.peers.iter().filter(|who| Some(*who) != from.as_ref()) {
+				ctx.send_message(who.clone(), Message::ChainSpecific(data.clone()));
+			}
+		}
+
+		let unauthorized: Vec<PeerId> = self.peers.iter()
+			.filter(|who| !self.is_authorized(who))
+			.cloned()
+			.collect();
+
+		for who in unauthorized {
+			info!(target: "poa", "Disconnecting deauthorized peer {}", who);
+			self.peers.remove(&who);
+			ctx.report_peer(who, Severity::Useless("Peer is no longer authorized on chain".into()));
+		}
+		self.metrics.set_peers(self.peers.len());
+	}
+}
+
+/// Keep the protocol state in sync with the chain at the best block.
+///
+/// Updates the authority set of `gossip`, and `authorized_peers` unless
+/// peer authorization is disabled locally. While `network` is major syncing,
+/// the registry at the best block may miss peers that were authorized since,
+/// so any peer is accepted until the node has caught up.
+pub fn sync_chain_state<B, E, N>(
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	network: Arc<N>,
+	authorized_peers: Option<AuthorizedPeers>,
+	gossip: Gossip,
+) -> impl Future<Item=(), Error=()> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+	N: SyncProvider<Block>,
+{
+	let syncing = Arc::new(AtomicBool::new(true));
+
+	let update = {
+		let syncing = syncing.clone();
+		move |client: &Client<B, E, Block, RuntimeApi>, at: BlockId<Block>| {
+			if let Some(ref authorized_peers) = authorized_peers {
+				if syncing.load(Ordering::Relaxed) {
+					*authorized_peers.write() = None;
+				} else {
+					update_authorized_peers(client, &at, authorized_peers);
+				}
+			}
+			match client.runtime_api().authorities(&at) {
+				Ok(authorities) => gossip.set_authorities(authorities),
+				Err(e) => warn!(target: "poa", "Unable to read authorities: {:?}", e),
+			}
+		}
+	};
+
+	if let Ok(info) = client.info() {
+		update(&client, BlockId::hash(info.chain.best_hash));
+	}
+
+	let imports = client.import_notification_stream()
+		.filter(|notification| notification.is_new_best)
+		.for_each(move |notification| {
+			update(&client, BlockId::hash(notification.hash));
+			Ok(())
+		});
+
+	let statuses = network.status().for_each(move |status| {
+		let is_syncing = status.sync.state == SyncState::Downloading;
+		if syncing.swap(is_syncing, Ordering::Relaxed) && !is_syncing {
+			debug!(target: "poa", "Caught up, enforcing authorized peers from the next block");
+		}
+		Ok(())
+	});
+
+	imports.join(statuses).map(|_| ())
+}
+
+fn update_authorized_peers<B, E>(
+	client: &Client<B, E, Block, RuntimeApi>,
+	at: &BlockId<Block>,
+	authorized_peers: &AuthorizedPeers,
+) where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let allowlist = match client.runtime_api().peer_allowlist(at) {
+		Ok(allowlist) => allowlist,
+		Err(e) => {
+			warn!(target: "poa", "Unable to read authorized peers: {:?}", e);
+			return;
+		}
+	};
+
+	let peers = allowlist.map(|peers| peers.iter()
+		.filter_map(|peer| {
+			let parsed = std::str::from_utf8(peer).ok().and_then(|s| s.parse::<PeerId>().ok());
+			if parsed.is_none() {
+				warn!(target: "poa", "Ignoring invalid authorized peer id {:?}", peer);
+			}
+			parsed
+		})
+		.collect::<HashSet<_>>()
+	);
+
+	*authorized_peers.write() = peers;
+}
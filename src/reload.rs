@@ -0,0 +1,133 @@
+//! Settings of a running node that are reloaded from a file on SIGHUP.
+
+use std::{collections::HashSet, fs, io, str::FromStr, sync::Arc};
+use futures::{Future, Stream};
+use log::{info, warn, LevelFilter};
+use serde_derive::Deserialize;
+use network::{ManageNetwork, PeerId};
+#[cfg(unix)]
+use tokio_signal::unix::{Signal, SIGHUP};
+
+/// Contents of the reload file, in TOML.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReloadConfig {
+	/// Maximum log level of all targets, e.g. `warn`.
+	///
+	/// The level can only be lowered from the one the node was started with:
+	/// records above the `--log` filters are dropped by the logger anyway.
+	/// Per-target filters cannot be changed while running.
+	pub log_level: Option<String>,
+	/// Multiaddresses of reserved peers, ending in `/p2p/<peer id>`.
+	pub reserved_peers: Vec<String>,
+}
+
+impl ReloadConfig {
+	/// Read the reload file at `path`.
+	pub fn from_file(path: &str) -> Result<Self, String> {
+		let data = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+		toml::from_str(&data).map_err(|e| format!("Invalid reload file {}: {}", path, e))
+	}
+}
+
+/// Applies the reload file to the node.
+pub struct Reloader<N> {
+	path: Option<String>,
+	network: Arc<N>,
+	// Maximum log level at startup, the highest level that can be set.
+	startup_level: LevelFilter,
+	// Reserved peers added from the file, as opposed to `--reserved-nodes`.
+	reserved_peers: HashSet<String>,
+}
+
+impl<N: ManageNetwork> Reloader<N> {
+	/// Create a reloader of the file at `path`, if any. Nothing is applied yet.
+	pub fn new(path: Option<String>, network: Arc<N>) -> Self {
+		Reloader { path, network, startup_level: log::max_level(), reserved_peers: HashSet::new() }
+	}
+
+	/// Read the file and apply it. The running settings are kept if the file is invalid.
+	pub fn reload(&mut self) {
+		let path = match self.path {
+			Some(ref path) => path,
+			None => {
+				info!(target: "poa", "Nothing to reload, the node was started without --reload-file");
+				return;
+			}
+		};
+		let config = match ReloadConfig::from_file(path) {
+			Ok(config) => config,
+			Err(e) => {
+				warn!(target: "poa", "Not reloading: {}", e);
+				return;
+			}
+		};
+
+		if let Some(level) = config.log_level {
+			match LevelFilter::from_str(&level) {
+				Ok(level) if level > self.startup_level => {
+					warn!(target: "poa", "Log level {} is above the startup level {}, using {}", level, self.startup_level, self.startup_level);
+					log::set_max_level(self.startup_level);
+				},
+				Ok(level) => log::set_max_level(level),
+				Err(_) if level.contains('=') => warn!(target: "poa", "Ignoring log level {}: per-target filters cannot be reloaded", level),
+				Err(_) => warn!(target: "poa", "Ignoring invalid log level {}", level),
+			}
+		}
+
+		let wanted: HashSet<String> = config.reserved_peers.into_iter().collect();
+		for addr in self.reserved_peers.difference(&wanted) {
+			match peer_id_of(addr) {
+				Some(peer) => self.network.remove_reserved_peer(peer),
+				None => warn!(target: "poa", "Unable to remove reserved peer {}", addr),
+			}
+		}
+
+		// Peers that could not be added are tried again on the next reload.
+		let mut reserved_peers = HashSet::new();
+		for addr in wanted {
+			if !self.reserved_peers.contains(&addr) {
+				if let Err(e) = self.network.add_reserved_peer(addr.clone()) {
+					warn!(target: "poa", "Unable to add reserved peer {}: {}", addr, e);
+					continue;
+				}
+			}
+			reserved_peers.insert(addr);
+		}
+		self.reserved_peers = reserved_peers;
+
+		info!(target: "poa", "Reloaded {}", path);
+	}
+}
+
+/// Apply the reload file now, if any, and each time the node receives SIGHUP.
+pub fn reload_on_hangup<N>(mut reloader: Reloader<N>) -> impl Future<Item=(), Error=()> where
+	N: ManageNetwork + Send + Sync + 'static,
+{
+	if reloader.path.is_some() {
+		reloader.reload();
+	}
+
+	hangups()
+		.for_each(move |_| {
+			reloader.reload();
+			Ok(())
+		})
+		.map_err(|e| warn!(target: "poa", "Unable to handle SIGHUP: {}", e))
+}
+
+#[cfg(unix)]
+fn hangups() -> impl Stream<Item=(), Error=io::Error> {
+	Signal::new(SIGHUP).flatten_stream().map(|_| ())
+}
+
+// There is no SIGHUP outside of Unix, so the file is only applied at startup.
+#[cfg(not(unix))]
+fn hangups() -> impl Stream<Item=(), Error=io::Error> {
+	futures::stream::empty()
+}
+
+// Peer id of a multiaddress ending in `/p2p/<peer id>`.
+fn peer_id_of(addr: &str) -> Option<PeerId> {
+	addr.rsplit("/p2p/").next()?.parse().ok()
+}
@@ -0,0 +1,151 @@
+//! PoA specific RPC methods, served next to the standard Substrate RPC.
+
+use std::{net::SocketAddr, sync::Arc};
+use futures::Future;
+use jsonrpc_core::{BoxFuture, Error, ErrorCode, IoHandler, Result};
+use jsonrpc_derive::rpc;
+use jsonrpc_http_server::{Server, ServerBuilder};
+use primitives::{ed25519, Blake2Hasher};
+use runtime_primitives::{generic::BlockId, traits::ProvideRuntimeApi};
+use substrate_client::{self as client, Client};
+use substrate_poa_runtime::{opaque::Block, AccountId, Hash, RuntimeApi, ValidatorSetApi, ValidatorStats};
+use crate::gossip::{Announcement, Gossip, SignedAnnouncement};
+use crate::seal::SealHandle;
+use crate::authoring::{AuthoringGuard, DuplicateAuthoring};
+
+/// PoA RPC methods.
+#[rpc]
+pub trait PoaApi {
+	/// Authorship counters of the given validator at the best block.
+	#[rpc(name = "poa_validatorStats")]
+	fn validator_stats(&self, account: AccountId) -> Result<ValidatorStats>;
+
+	/// Authorship counters of all current validators at the best block.
+	#[rpc(name = "poa_allValidatorStats")]
+	fn all_validator_stats(&self) -> Result<Vec<(AccountId, ValidatorStats)>>;
+
+	/// Latest announcements gossiped by each authority.
+	#[rpc(name = "poa_announcements")]
+	fn announcements(&self) -> Result<Vec<SignedAnnouncement>>;
+
+	/// Announce a maintenance window of the local authority, as unix timestamps in seconds.
+	#[rpc(name = "poa_announceMaintenance")]
+	fn announce_maintenance(&self, start: u64, end: u64, note: String) -> Result<SignedAnnouncement>;
+
+	/// Announce that the local authority is about to rotate to a new session key.
+	#[rpc(name = "poa_announceKeyRotation")]
+	fn announce_key_rotation(&self, new_key: ed25519::Public) -> Result<SignedAnnouncement>;
+
+	/// Block authored with a local key by another node, which stopped local authoring.
+	#[rpc(name = "poa_authoringAlert")]
+	fn authoring_alert(&self) -> Result<Option<DuplicateAuthoring>>;
+}
+
+/// Block authoring control of the dev seal mode.
+#[rpc]
+pub trait EngineApi {
+	/// Seal a new block on top of the best block and return its hash.
+	#[rpc(name = "engine_createBlock")]
+	fn create_block(&self) -> BoxFuture<Hash>;
+}
+
+/// Implementation of `EngineApi` backed by the seal task.
+pub struct Engine {
+	seal: SealHandle,
+}
+
+impl EngineApi for Engine {
+	fn create_block(&self) -> BoxFuture<Hash> {
+		Box::new(self.seal.create_block().map_err(internal_error))
+	}
+}
+
+/// Implementation of `PoaApi` backed by a client.
+pub struct Poa<B, E> {
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	gossip: Gossip,
+	guard: AuthoringGuard,
+}
+
+impl<B, E> Poa<B, E> {
+	/// Create a new `Poa` RPC handler.
+	pub fn new(client: Arc<Client<B, E, Block, RuntimeApi>>, gossip: Gossip, guard: AuthoringGuard) -> Self {
+		Poa { client, gossip, guard }
+	}
+}
+
+impl<B, E> Poa<B, E> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	fn best_block(&self) -> Result<BlockId<Block>> {
+		let info = self.client.info().map_err(internal_error)?;
+		Ok(BlockId::hash(info.chain.best_hash))
+	}
+}
+
+impl<B, E> PoaApi for Poa<B, E> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	fn validator_stats(&self, account: AccountId) -> Result<ValidatorStats> {
+		let at = self.best_block()?;
+		self.client.runtime_api().validator_stats(&at, account).map_err(internal_error)
+	}
+
+	fn all_validator_stats(&self) -> Result<Vec<(AccountId, ValidatorStats)>> {
+		let at = self.best_block()?;
+		self.client.runtime_api().all_validator_stats(&at).map_err(internal_error)
+	}
+
+	fn announcements(&self) -> Result<Vec<SignedAnnouncement>> {
+		Ok(self.gossip.announcements())
+	}
+
+	fn announce_maintenance(&self, start: u64, end: u64, note: String) -> Result<SignedAnnouncement> {
+		if end <= start {
+			return Err(Error::invalid_params("Maintenance window must end after it starts"));
+		}
+		self.gossip.announce(Announcement::Maintenance { start, end, note }).map_err(internal_error)
+	}
+
+	fn announce_key_rotation(&self, new_key: ed25519::Public) -> Result<SignedAnnouncement> {
+		self.gossip.announce(Announcement::KeyRotation { new_key }).map_err(internal_error)
+	}
+
+	fn authoring_alert(&self) -> Result<Option<DuplicateAuthoring>> {
+		Ok(self.guard.alert())
+	}
+}
+
+fn internal_error<T: std::fmt::Debug>(e: T) -> Error {
+	Error {
+		code: ErrorCode::InternalError,
+		message: format!("{:?}", e),
+		data: None,
+	}
+}
+
+/// Start the PoA RPC HTTP server on the given address.
+///
+/// The engine methods are only served if `seal` is given.
+pub fn start_http<B, E>(
+	addr: &SocketAddr,
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	gossip: Gossip,
+	guard: AuthoringGuard,
+	seal: Option<SealHandle>,
+) -> std::io::Result<Server> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let mut io = IoHandler::new();
+	io.extend_with(Poa::new(client, gossip, guard).to_delegate());
+	if let Some(seal) = seal {
+		io.extend_with(Engine { seal }.to_delegate());
+	}
+
+	ServerBuilder::new(io)
+		.threads(1)
+		.start_http(addr)
+}
@@ -0,0 +1,251 @@
+//! Instant and manual block sealing for development.
+//!
+//! Replaces the Aura slot worker on the dev chain, so that blocks are authored
+//! as soon as they are needed instead of once per slot. Sealed blocks still
+//! carry a regular Aura seal, but they are handed to the block import directly
+//! and skip the Aura verifier, so its slot and clock checks never run on them.
+//!
+//! Every block takes a slot of its own, so when blocks are sealed faster than
+//! once per slot the chain time runs ahead of the wall clock. Until the clock
+//! catches up, other nodes reject the blocks as coming from the future.
+
+use std::{str::FromStr, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use futures::{Future, IntoFuture, Stream, sync::{mpsc, oneshot}};
+use log::{info, warn};
+use parity_codec::{Encode, Decode};
+use consensus::CompatibleDigestItem;
+use consensus_authorities::AuthoritiesApi;
+use consensus_common::{BlockImport, BlockOrigin, Environment, ForkChoiceStrategy, ImportBlock, Proposer};
+use inherents::InherentData;
+use primitives::{ed25519::Pair, Pair as PairT, Blake2Hasher, storage::StorageKey, twox_128};
+use runtime_primitives::{generic::BlockId, traits::{Block as BlockT, Header as HeaderT, DigestItemFor, ProvideRuntimeApi}};
+use substrate_client::{self as client, Client};
+use substrate_poa_runtime::{opaque::Block, Hash, RuntimeApi};
+
+/// How blocks are sealed in development mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SealMode {
+	/// Seal a block as soon as a transaction enters the pool.
+	Instant,
+	/// Only seal blocks on `engine_createBlock` calls.
+	Manual,
+}
+
+impl FromStr for SealMode {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"instant" => Ok(SealMode::Instant),
+			"manual" => Ok(SealMode::Manual),
+			other => Err(format!("Unknown seal mode {}, expected `instant` or `manual`", other)),
+		}
+	}
+}
+
+/// Channel to answer a seal request with the hash of the new block.
+pub type SealReply = oneshot::Sender<Result<Hash, String>>;
+
+/// Handle to request blocks from the seal task.
+#[derive(Clone)]
+pub struct SealHandle {
+	sender: mpsc::UnboundedSender<SealReply>,
+}
+
+impl SealHandle {
+	/// Request a new block, resolving to its hash once it is imported.
+	pub fn create_block(&self) -> impl Future<Item=Hash, Error=String> {
+		let (reply, result) = oneshot::channel();
+		let sent = self.sender.unbounded_send(reply).map_err(|_| "Seal task is not running".to_string());
+
+		sent.into_future()
+			.and_then(|_| result.map_err(|_| "Seal task is not running".to_string()))
+			.and_then(|result| result)
+	}
+}
+
+/// Create a handle and the stream of requests it sends.
+pub fn channel() -> (SealHandle, mpsc::UnboundedReceiver<SealReply>) {
+	let (sender, receiver) = mpsc::unbounded();
+	(SealHandle { sender }, receiver)
+}
+
+/// Seal a block with `key` on top of the best block for each trigger.
+///
+/// Triggers carrying a reply channel are answered with the result.
+pub fn run_dev_seal<B, E, Env, I, S>(
+	slot_duration: u64,
+	key: Arc<Pair>,
+	client: Arc<Client<B, E, Block, RuntimeApi>>,
+	block_import: Arc<I>,
+	env: Arc<Env>,
+	triggers: S,
+) -> impl Future<Item=(), Error=()> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+	Env: Environment<Block>,
+	Env::Error: std::fmt::Debug,
+	<Env::Proposer as Proposer<Block>>::Error: std::fmt::Debug,
+	I: BlockImport<Block>,
+	I::Error: std::fmt::Debug,
+	S: Stream<Item=Option<SealReply>, Error=()>,
+{
+	triggers.for_each(move |reply| {
+		let key = key.clone();
+		let block_import = block_import.clone();
+
+		propose(slot_duration, &key, &client, &*env)
+			.into_future()
+			.and_then(|(proposing, slot_num)| proposing.map(move |block| (block, slot_num)))
+			.and_then(move |(block, slot_num)| import_sealed(block, slot_num, &key, &*block_import))
+			.then(move |result| {
+				match result {
+					Ok(ref hash) => info!(target: "poa", "Sealed block {}", hash),
+					Err(ref e) => warn!(target: "poa", "Unable to seal block: {}", e),
+				}
+				if let Some(reply) = reply {
+					let _ = reply.send(result);
+				}
+				Ok(())
+			})
+	})
+}
+
+// Starts proposing a block on top of the best block, in the next slot of `key`.
+//
+// Resolves to the unsealed block and its slot.
+fn propose<B, E, Env>(
+	slot_duration: u64,
+	key: &Pair,
+	client: &Client<B, E, Block, RuntimeApi>,
+	env: &Env,
+) -> Result<(impl Future<Item=Block, Error=String>, u64), String> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+	Env: Environment<Block>,
+	Env::Error: std::fmt::Debug,
+	<Env::Proposer as Proposer<Block>>::Error: std::fmt::Debug,
+{
+	let best_hash = client.info().map_err(|e| format!("{:?}", e))?.chain.best_hash;
+	let at = BlockId::hash(best_hash);
+	let parent = client.header(&at)
+		.map_err(|e| format!("{:?}", e))?
+		.ok_or_else(|| format!("Best block {} not found", best_hash))?;
+
+	let authorities = client.runtime_api().authorities(&at).map_err(|e| format!("{:?}", e))?;
+	let position = authorities.iter().position(|a| *a == key.public())
+		.ok_or_else(|| format!("Key {} is not an authority", key.public()))?;
+
+	// Every block needs a slot of its own, so the chain time runs ahead of
+	// the clock when blocks are sealed faster than once per slot.
+	let earliest = std::cmp::max(now_secs(), last_timestamp(client, &at)? + slot_duration);
+	let slot_num = next_owned_slot(earliest / slot_duration, position, authorities.len());
+	let timestamp = std::cmp::max(earliest, slot_num * slot_duration);
+
+	let mut inherent_data = InherentData::new();
+	inherent_data.put_data(timestamp::INHERENT_IDENTIFIER, &timestamp).map_err(|e| format!("{:?}", e))?;
+
+	let proposer = env.init(&parent, &authorities).map_err(|e| format!("{:?}", e))?;
+	let proposing = proposer.propose(inherent_data, Duration::from_secs(slot_duration))
+		.into_future()
+		.map_err(|e| format!("{:?}", e));
+	Ok((proposing, slot_num))
+}
+
+// First slot from `slot` on that Aura assigns to the authority at `position`,
+// which is `authorities[slot % count]`.
+fn next_owned_slot(slot: u64, position: usize, count: usize) -> u64 {
+	let count = count as u64;
+	slot + (position as u64 + count - slot % count) % count
+}
+
+// Seals `block` for `slot_num` with `key` and imports it.
+fn import_sealed<I>(block: Block, slot_num: u64, key: &Pair, block_import: &I) -> Result<Hash, String> where
+	I: BlockImport<Block>,
+	I::Error: std::fmt::Debug,
+{
+	let (header, body) = block.deconstruct();
+	let signature = key.sign(&(slot_num, header.hash()).encode()[..]);
+	let seal = <DigestItemFor<Block> as CompatibleDigestItem<Pair>>::aura_seal(slot_num, signature);
+
+	let mut sealed = header.clone();
+	sealed.digest_mut().push(seal.clone());
+
+	let import_block = ImportBlock {
+		origin: BlockOrigin::Own,
+		header,
+		justification: None,
+		post_digests: vec![seal],
+		body: Some(body),
+		finalized: false,
+		auxiliary: Vec::new(),
+		fork_choice: ForkChoiceStrategy::LongestChain,
+	};
+	block_import.import_block(import_block, None).map_err(|e| format!("{:?}", e))?;
+
+	Ok(sealed.hash())
+}
+
+// Timestamp of the given block, as stored by the timestamp module.
+fn last_timestamp<B, E>(client: &Client<B, E, Block, RuntimeApi>, at: &BlockId<Block>) -> Result<u64, String> where
+	B: client::backend::Backend<Block, Blake2Hasher> + Send + Sync + 'static,
+	E: client::CallExecutor<Block, Blake2Hasher> + Send + Sync + 'static,
+{
+	let key = StorageKey(twox_128(b"Timestamp Now").to_vec());
+	let data = client.storage(at, &key).map_err(|e| format!("{:?}", e))?;
+	Ok(data.and_then(|data| u64::decode(&mut &data.0[..])).unwrap_or(0))
+}
+
+fn now_secs() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use basic_authorship::ProposerFactory;
+	use runtime_primitives::traits::Digest;
+	use transaction_pool::txpool::Pool;
+	use crate::service::test_client;
+
+	#[test]
+	fn next_owned_slot_follows_aura_order() {
+		assert_eq!(next_owned_slot(10, 0, 1), 10);
+		assert_eq!(next_owned_slot(10, 1, 3), 10);
+		assert_eq!(next_owned_slot(10, 2, 3), 11);
+		assert_eq!(next_owned_slot(10, 0, 3), 12);
+		assert_eq!(next_owned_slot(12, 0, 3), 12);
+	}
+
+	#[test]
+	fn sealed_block_is_imported_and_finalized() {
+		let client = Arc::new(test_client());
+		let proposer = Arc::new(ProposerFactory {
+			client: client.clone(),
+			transaction_pool: Arc::new(Pool::new(Default::default(), transaction_pool::ChainApi::new(client.clone()))),
+			inherents_pool: Default::default(),
+		});
+		let key = Arc::new(Pair::from_string("//Alice", None).unwrap());
+
+		let (handle, requests) = channel();
+		let created = handle.create_block();
+		drop(handle);
+		run_dev_seal(6, key, client.clone(), client.clone(), proposer, requests.map(Some)).wait().unwrap();
+		let hash = created.wait().unwrap();
+
+		let info = client.info().unwrap().chain;
+		assert_eq!(info.best_hash, hash);
+		assert_eq!(info.best_number, 1);
+
+		// Alice comes first of the two local testnet authorities.
+		let header = client.header(&BlockId::hash(hash)).unwrap().unwrap();
+		let slot = header.digest().logs().iter()
+			.find_map(|item| <DigestItemFor<Block> as CompatibleDigestItem<Pair>>::as_aura_seal(item))
+			.map(|(slot, _)| slot)
+			.unwrap();
+		assert_eq!(slot % 2, 0);
+
+		client.finalize_block(BlockId::hash(hash), None, true).unwrap();
+		assert_eq!(client.info().unwrap().chain.finalized_hash, hash);
+	}
+}
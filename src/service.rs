@@ -3,22 +3,32 @@
 #![warn(unused_extern_crates)]
 
 use std::sync::Arc;
+use std::net::SocketAddr;
+use std::time::Duration;
+use futures::{Future, Stream};
 use log::info;
 use transaction_pool::{self, txpool::{Pool as TransactionPool}};
 use substrate_poa_runtime::{self, GenesisConfig, opaque::Block, RuntimeApi};
 use substrate_service::{
 	FactoryFullConfiguration, LightComponents, FullComponents, FullBackend,
 	FullClient, LightClient, LightBackend, FullExecutor, LightExecutor,
-	TaskExecutor,
+	TaskExecutor, ServiceFactory, Roles,
 };
 use basic_authorship::ProposerFactory;
 use consensus::{import_queue, start_aura, AuraImportQueue, SlotDuration, NothingExtra};
 use substrate_client as client;
 use primitives::{ed25519::Pair, Pair as PairT};
 use inherents::InherentDataProviders;
-use network::construct_simple_protocol;
 use substrate_executor::native_executor_instance;
 use substrate_service::construct_service_factory;
+use crate::rpc;
+use crate::authoring::{self, AuthoringGuard, AuthoringTasks};
+use crate::equivocation;
+use crate::protocol::{self, NodeProtocol, AuthorizedPeers};
+use crate::gossip::{self, Gossip};
+use crate::seal::{self, SealMode, SealReply};
+use crate::metrics::{self, Metrics};
+use crate::health::{self, Thresholds};
 
 pub use substrate_executor::NativeExecutor;
 // Our native executor instance.
@@ -29,55 +39,278 @@ native_executor_instance!(
 	include_bytes!("../runtime/wasm/target/wasm32-unknown-unknown/release/substrate_poa_runtime_wasm.compact.wasm")
 );
 
-#[derive(Default)]
-pub struct NodeConfig {
+pub struct NodeConfig<F: ServiceFactory> {
+	/// GRANDPA block import and link half, created with the import queue
+	/// and handed over to the voter in the authority setup.
+	pub grandpa_import_setup: Option<(Arc<grandpa::BlockImportForService<F>>, grandpa::LinkHalfForService<F>)>,
 	inherent_data_providers: InherentDataProviders,
+	/// Port of the PoA RPC server. The server is not started if `None`.
+	pub poa_rpc_port: Option<u16>,
+	/// Accept any peer, regardless of the on-chain registry.
+	pub disable_peer_authorization: bool,
+	/// Seal blocks on demand instead of running Aura.
+	pub dev_seal: Option<SealMode>,
+	/// Address of the metrics server. The server is not started if `None`.
+	pub metrics_addr: Option<SocketAddr>,
+	/// Address of the health probe server. The server is not started if `None`.
+	pub health_addr: Option<SocketAddr>,
+	/// Limits beyond which the health probes fail.
+	pub health_thresholds: Thresholds,
+	/// Fires when authoring should stop ahead of a shutdown.
+	pub authoring_pause: Option<exit_future::Exit>,
+	/// Running authoring tasks, waited for after the pause.
+	pub authoring_tasks: AuthoringTasks,
+	authorized_peers: AuthorizedPeers,
+	gossip: Gossip,
+	authoring_guard: AuthoringGuard,
+	metrics: Metrics,
 }
 
-construct_simple_protocol! {
-	/// Demo protocol attachment for substrate.
-	pub struct NodeProtocol where Block = Block { }
+impl<F> Default for NodeConfig<F> where F: ServiceFactory {
+	fn default() -> NodeConfig<F> {
+		NodeConfig {
+			grandpa_import_setup: None,
+			inherent_data_providers: InherentDataProviders::new(),
+			poa_rpc_port: None,
+			disable_peer_authorization: false,
+			dev_seal: None,
+			metrics_addr: None,
+			health_addr: None,
+			health_thresholds: Default::default(),
+			authoring_pause: None,
+			authoring_tasks: Default::default(),
+			authorized_peers: Default::default(),
+			gossip: Default::default(),
+			authoring_guard: Default::default(),
+			metrics: Default::default(),
+		}
+	}
 }
 
 construct_service_factory! {
 	struct Factory {
 		Block = Block,
 		RuntimeApi = RuntimeApi,
-		NetworkProtocol = NodeProtocol { |config| Ok(NodeProtocol::new()) },
+		NetworkProtocol = NodeProtocol { |config: &FactoryFullConfiguration<Self>|
+			Ok(NodeProtocol::new(
+				config.custom.authorized_peers.clone(),
+				config.custom.gossip.clone(),
+				config.custom.metrics.clone(),
+			))
+		},
 		RuntimeDispatch = Executor,
 		FullTransactionPoolApi = transaction_pool::ChainApi<client::Client<FullBackend<Self>, FullExecutor<Self>, Block, RuntimeApi>, Block>
 			{ |config, client| Ok(TransactionPool::new(config, transaction_pool::ChainApi::new(client))) },
 		LightTransactionPoolApi = transaction_pool::ChainApi<client::Client<LightBackend<Self>, LightExecutor<Self>, Block, RuntimeApi>, Block>
 			{ |config, client| Ok(TransactionPool::new(config, transaction_pool::ChainApi::new(client))) },
 		Genesis = GenesisConfig,
-		Configuration = NodeConfig,
+		Configuration = NodeConfig<Self>,
 		FullService = FullComponents<Self>
 			{ |config: FactoryFullConfiguration<Self>, executor: TaskExecutor|
 				FullComponents::<Factory>::new(config, executor)
 			},
 		AuthoritySetup = {
-			|service: Self::FullService, executor: TaskExecutor, key: Option<Arc<Pair>>| {
-				if let Some(key) = key {
-					info!("Using authority key {}", key.public());
+			|mut service: Self::FullService, executor: TaskExecutor, key: Option<Arc<Pair>>| {
+				let (block_import, link_half) = service.config.custom.grandpa_import_setup.take()
+					.expect("Link Half and Block Import are present for Full Services or setup failed before. qed");
+
+				let dev_seal = service.config.custom.dev_seal;
+				let (seal_handle, seal_requests) = match dev_seal {
+					Some(_) if key.is_none() => return Err("--dev-seal requires an authority key".into()),
+					Some(_) => {
+						let (handle, requests) = seal::channel();
+						(Some(handle), Some(requests))
+					},
+					None => (None, None),
+				};
+
+				if let Some(port) = service.config.custom.poa_rpc_port {
+					let addr = SocketAddr::from(([127, 0, 0, 1], port));
+					let server = rpc::start_http(
+						&addr,
+						service.client(),
+						service.config.custom.gossip.clone(),
+						service.config.custom.authoring_guard.clone(),
+						seal_handle,
+					)
+						.map_err(|e| format!("Unable to start PoA RPC server on {}: {}", addr, e))?;
+					info!("PoA RPC server listening on {}", addr);
+					executor.spawn(service.on_exit().then(move |_| {
+						server.close();
+						Ok(())
+					}));
+				}
+
+				if let Some(addr) = service.config.custom.metrics_addr {
+					let server = metrics::start_http(
+						&addr,
+						service.client(),
+						service.transaction_pool(),
+						service.config.custom.metrics.clone(),
+					)?;
+					info!("Metrics server listening on {}", addr);
+					executor.spawn(server.select(service.on_exit()).then(|_| Ok(())));
+				}
+
+				let authorized_peers = if service.config.custom.disable_peer_authorization {
+					None
+				} else {
+					Some(service.config.custom.authorized_peers.clone())
+				};
+				let sync = protocol::sync_chain_state(
+					service.client(),
+					service.network(),
+					authorized_peers,
+					service.config.custom.gossip.clone(),
+				);
+				executor.spawn(sync.select(service.on_exit()).then(|_| Ok(())));
+
+				let reports = equivocation::report_equivocations(service.client(), service.transaction_pool());
+				executor.spawn(reports.select(service.on_exit()).then(|_| Ok(())));
+
+				// Only authorities author; the keystore of other nodes holds a generated key.
+				let keys = if service.config.roles == Roles::AUTHORITY {
+					let password: &str = &service.config.password;
+					authoring::LocalKeys::new(key, &service.config.keystore_path, password)
+				} else {
+					Default::default()
+				};
+				let grandpa_key = authoring::authority_key(&service.client(), &keys.get())
+					.or_else(|| keys.get().first().cloned());
+
+				if let Some(addr) = service.config.custom.health_addr {
+					let status = health::NetworkStatus::default();
+					let network = health::track_network(service.network(), status.clone());
+					executor.spawn(network.select(service.on_exit()).then(|_| Ok(())));
+
+					let server = health::start_http(
+						&addr,
+						service.client(),
+						status,
+						keys.clone(),
+						service.config.custom.health_thresholds.clone(),
+					)?;
+					info!("Health server listening on {}", addr);
+					executor.spawn(server.select(service.on_exit()).then(|_| Ok(())));
+				}
+
+				if !keys.get().is_empty() && service.config.custom.metrics_addr.is_some() {
+					let slots = metrics::track_slots(
+						service.client(),
+						keys.clone(),
+						service.config.custom.metrics.clone(),
+						SlotDuration::get_or_compute(&*service.client())?.get(),
+					);
+					executor.spawn(slots.select(service.on_exit()).then(|_| Ok(())));
+				}
+
+				if let Some(key) = keys.get().first().cloned() {
+					let heartbeats = gossip::heartbeats(service.client(), service.config.custom.gossip.clone());
+					executor.spawn(heartbeats.select(service.on_exit()).then(|_| Ok(())));
+
 					let proposer = Arc::new(ProposerFactory {
 						client: service.client(),
 						transaction_pool: service.transaction_pool(),
 						inherents_pool: service.inherents_pool(),
 					});
 					let client = service.client();
-					executor.spawn(start_aura(
-						SlotDuration::get_or_compute(&*client)?,
-						key.clone(),
-						client.clone(),
-						client,
-						proposer,
-						service.network(),
-						service.on_exit(),
-						service.config.custom.inherent_data_providers.clone(),
-						service.config.force_authoring,
-					)?);
+
+					// Authoring stops when the service exits or is paused for shutdown.
+					let authoring_exit = match service.config.custom.authoring_pause.clone() {
+						Some(pause) => {
+							let (signal, exit) = exit_future::signal();
+							executor.spawn(service.on_exit().select(pause).then(move |_| {
+								signal.fire();
+								Ok(())
+							}));
+							exit
+						},
+						None => service.on_exit(),
+					};
+
+					if let (Some(mode), Some(requests)) = (dev_seal, seal_requests) {
+						info!("Sealing blocks in {:?} mode with key {}", mode, key.public());
+						service.config.custom.gossip.set_local_key(key.clone());
+						let requests = requests.map(Some);
+						let triggers: Box<dyn Stream<Item=Option<SealReply>, Error=()> + Send> = match mode {
+							SealMode::Instant => Box::new(requests.select(
+								service.transaction_pool().import_notification_stream().map(|_| None)
+							)),
+							SealMode::Manual => Box::new(requests),
+						};
+						let sealing = seal::run_dev_seal(
+							SlotDuration::get_or_compute(&*client)?.get(),
+							key.clone(),
+							client,
+							block_import.clone(),
+							proposer,
+							triggers,
+						);
+						let sealing = service.config.custom.authoring_tasks.track(sealing.select(authoring_exit));
+						executor.spawn(sealing.then(|_| Ok(())));
+					} else {
+						let slot_duration = SlotDuration::get_or_compute(&*client)?;
+						let network = service.network();
+						let on_exit = authoring_exit.clone();
+						let inherent_data_providers = service.config.custom.inherent_data_providers.clone();
+						let force_authoring = service.config.force_authoring;
+						let aura_executor = executor.clone();
+						let aura_client = client.clone();
+						let aura_tasks = service.config.custom.authoring_tasks.clone();
+						let block_import = block_import.clone();
+
+						let start = move |key: Arc<Pair>, stop: exit_future::Exit| {
+							let aura = start_aura(
+								slot_duration.clone(),
+								key,
+								aura_client.clone(),
+								block_import.clone(),
+								proposer.clone(),
+								network.clone(),
+								on_exit.clone().select(stop).then(|_| Ok(())),
+								inherent_data_providers.clone(),
+								force_authoring,
+							).map_err(|e| format!("{:?}", e))?;
+							aura_executor.spawn(aura_tasks.track(aura));
+							Ok(())
+						};
+						let authoring = authoring::follow_authorities(
+							client,
+							keys,
+							grandpa_key.as_ref().map(|key| key.public()),
+							service.config.custom.gossip.clone(),
+							service.config.custom.authoring_guard.clone(),
+							slot_duration.get(),
+							start,
+						);
+						executor.spawn(authoring.select(authoring_exit).then(|_| Ok(())));
+					}
+				}
+
+				// Without a key the node follows finality without voting.
+				let local_key = if service.config.disable_grandpa {
+					None
+				} else {
+					grandpa_key
+				};
+				if let Some(ref key) = local_key {
+					info!("Running GRANDPA session as authority {}", key.public());
 				}
 
+				executor.spawn(grandpa::run_grandpa(
+					grandpa::Config {
+						local_key,
+						gossip_duration: Duration::from_millis(333),
+						justification_period: 4096,
+						name: Some(service.config.name.clone()),
+					},
+					link_half,
+					grandpa::NetworkBridge::new(service.network()),
+					service.config.custom.inherent_data_providers.clone(),
+					// Voting stops along with authoring when a local key is used by another node.
+					service.on_exit().select(service.config.custom.authoring_guard.on_trip()).then(|_| Ok(())),
+				)?);
+
 				Ok(service)
 			}
 		},
@@ -87,10 +320,20 @@ construct_service_factory! {
 			Self::Block,
 		>
 			{ |config: &mut FactoryFullConfiguration<Self> , client: Arc<FullClient<Self>>| {
+					let slot_duration = SlotDuration::get_or_compute(&*client)?;
+					let (block_import, link_half) =
+						grandpa::block_import::<_, _, _, RuntimeApi, FullClient<Self>>(
+							client.clone(), client.clone()
+						)?;
+					let block_import = Arc::new(block_import);
+					let justification_import = block_import.clone();
+
+					config.custom.grandpa_import_setup = Some((block_import.clone(), link_half));
+
 					import_queue::<_, _, _, Pair>(
-						SlotDuration::get_or_compute(&*client)?,
-						client.clone(),
-						None,
+						slot_duration,
+						Arc::new(metrics::TimedImport::new(block_import, config.custom.metrics.clone())),
+						Some(justification_import),
 						client,
 						NothingExtra,
 						config.custom.inherent_data_providers.clone(),
@@ -113,3 +356,15 @@ construct_service_factory! {
 			},
 	}
 }
+
+/// Client of the local testnet with an in-memory database, for tests.
+#[cfg(test)]
+pub fn test_client() -> client::Client<
+	client::in_mem::Backend<Block, primitives::Blake2Hasher>,
+	client::LocalCallExecutor<client::in_mem::Backend<Block, primitives::Blake2Hasher>, NativeExecutor<Executor>>,
+	Block,
+	RuntimeApi,
+> {
+	let spec = crate::chain_spec::Alternative::LocalTestnet.load().expect("local testnet is valid");
+	client::new_in_mem(NativeExecutor::new(None), spec).expect("genesis block can be built")
+}